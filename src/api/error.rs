@@ -20,6 +20,21 @@ pub enum ResponseError {
 
     #[error("run failed: {0}")]
     RunFailed(runner::error::Error),
+
+    #[error("invalid decision '{0}', must be 'approve' or 'reject'")]
+    InvalidDecision(String),
+
+    #[error("missing bearer token in authorization header")]
+    MissingToken,
+
+    #[error("invalid path segment: {0}")]
+    InvalidPathSegment(String),
+
+    #[error("{0} not found")]
+    NotFound(&'static str),
+
+    #[error("failed reading artifact: {0}")]
+    ReadArtifact(std::io::Error),
 }
 
 impl Reject for ResponseError {}
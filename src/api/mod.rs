@@ -2,10 +2,14 @@ pub mod error;
 mod util;
 
 use self::error::ResponseError;
-use crate::{config::Config, runner::Runner};
+use crate::{
+    config::Config,
+    runner::{self, Runner},
+};
 use error::Result;
+use serde::Deserialize;
 use std::convert::Infallible;
-use util::str_to_ip;
+use util::{decode_segment, str_to_ip};
 use warp::{
     hyper::{body::Bytes, StatusCode},
     Filter, Rejection, Reply,
@@ -18,7 +22,65 @@ pub async fn run(cfg: &Config, runner: Runner) -> Result<()> {
         .and(with_runner(runner.clone()))
         .and_then(handle_postreceive);
 
-    let api = warp::path("api").and(postreceive.recover(handle_error));
+    let environments = warp::path("environments")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_runner(runner.clone()))
+        .and_then(handle_environments);
+
+    let deployments = warp::path!("environments" / String / "deployments")
+        .and(warp::get())
+        .and(warp::query::<EnvironmentQuery>())
+        .and(with_runner(runner.clone()))
+        .and_then(handle_deployments);
+
+    let rollback = warp::path!("environments" / String / "rollback")
+        .and(warp::post())
+        .and(warp::query::<EnvironmentQuery>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_runner(runner.clone()))
+        .and_then(handle_rollback);
+
+    let approvals = warp::path("approvals")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_runner(runner.clone()))
+        .and_then(handle_approvals);
+
+    let decide_approval = warp::path!("approvals" / u64 / String)
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_runner(runner.clone()))
+        .and_then(handle_decide_approval);
+
+    let runs = warp::path("runs")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_runner(runner.clone()))
+        .and_then(handle_runs);
+
+    let run = warp::path!("runs" / u64)
+        .and(warp::get())
+        .and(with_runner(runner.clone()))
+        .and_then(handle_run);
+
+    let artifact = warp::path!("runs" / u64 / "artifacts" / String)
+        .and(warp::get())
+        .and(with_runner(runner.clone()))
+        .and_then(handle_artifact);
+
+    let api = warp::path("api").and(
+        postreceive
+            .or(environments)
+            .or(deployments)
+            .or(rollback)
+            .or(approvals)
+            .or(decide_approval)
+            .or(runs)
+            .or(run)
+            .or(artifact)
+            .recover(handle_error),
+    );
 
     warp::serve(api)
         .run((
@@ -30,6 +92,11 @@ pub async fn run(cfg: &Config, runner: Runner) -> Result<()> {
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct EnvironmentQuery {
+    remote: Option<String>,
+}
+
 fn with_runner(runner: Runner) -> impl Filter<Extract = (Runner,), Error = Infallible> + Clone {
     warp::any().map(move || runner.clone())
 }
@@ -47,12 +114,37 @@ async fn handle_error(err: Rejection) -> Result<impl Reply, Infallible> {
     if let Some(err) = err.find::<ResponseError>() {
         #[allow(clippy::single_match)]
         match err {
-            ResponseError::MissingBodyArgs(_) | ResponseError::InvalidBodyFormat(_) => {
+            ResponseError::MissingBodyArgs(_)
+            | ResponseError::InvalidBodyFormat(_)
+            | ResponseError::InvalidDecision(_)
+            | ResponseError::InvalidPathSegment(_)
+            | ResponseError::RunFailed(runner::error::Error::AmbiguousEnvironment(_)) => {
                 return Ok(warp::reply::with_status(
                     err.to_string(),
                     StatusCode::BAD_REQUEST,
                 ))
             }
+            ResponseError::MissingToken
+            | ResponseError::RunFailed(
+                runner::error::Error::ApprovalUnauthorized(_)
+                | runner::error::Error::RollbackUnauthorized(_),
+            ) => {
+                return Ok(warp::reply::with_status(
+                    err.to_string(),
+                    StatusCode::UNAUTHORIZED,
+                ))
+            }
+            ResponseError::NotFound(_)
+            | ResponseError::RunFailed(
+                runner::error::Error::EnvironmentNotFound(_)
+                | runner::error::Error::NoRollbackTarget(_)
+                | runner::error::Error::ApprovalNotFound(_),
+            ) => {
+                return Ok(warp::reply::with_status(
+                    err.to_string(),
+                    StatusCode::NOT_FOUND,
+                ))
+            }
             _ => {}
         }
     }
@@ -78,11 +170,107 @@ async fn handle_postreceive(body: Bytes, runner: Runner) -> Result<impl Reply, R
     let reference_name = args
         .next()
         .ok_or(ResponseError::MissingBodyArgs("reference name parameter"))?;
+    let pusher = args.next().map(str::trim).unwrap_or("unknown");
+    let before = args.next().map(str::trim);
 
     runner
-        .run(remote_repo, reference, reference_name)
+        .run(remote_repo, reference, reference_name, pusher, before)
         .await
         .map_err(ResponseError::RunFailed)?;
 
     Ok(StatusCode::OK)
 }
+
+async fn handle_environments(runner: Runner) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&runner.environments()))
+}
+
+async fn handle_deployments(
+    environment: String,
+    query: EnvironmentQuery,
+    runner: Runner,
+) -> Result<impl Reply, Rejection> {
+    let environment = decode_segment(&environment)?;
+    Ok(warp::reply::json(
+        &runner.deployments(query.remote.as_deref(), &environment),
+    ))
+}
+
+async fn handle_rollback(
+    environment: String,
+    query: EnvironmentQuery,
+    authorization: Option<String>,
+    runner: Runner,
+) -> Result<impl Reply, Rejection> {
+    let environment = decode_segment(&environment)?;
+    let token = bearer_token(authorization.as_deref())?;
+
+    let deployment = runner
+        .rollback(&environment, query.remote.as_deref(), token)
+        .await
+        .map_err(ResponseError::RunFailed)?;
+
+    Ok(warp::reply::json(&deployment))
+}
+
+async fn handle_approvals(runner: Runner) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&runner.pending_approvals()))
+}
+
+async fn handle_decide_approval(
+    id: u64,
+    decision: String,
+    authorization: Option<String>,
+    runner: Runner,
+) -> Result<impl Reply, Rejection> {
+    let approve = match decision.as_str() {
+        "approve" => true,
+        "reject" => false,
+        _ => return Err(ResponseError::InvalidDecision(decision).into()),
+    };
+
+    let token = bearer_token(authorization.as_deref())?;
+
+    let approval = runner
+        .decide_approval(id, token, approve)
+        .map_err(ResponseError::RunFailed)?;
+
+    Ok(warp::reply::json(&approval))
+}
+
+fn bearer_token(authorization: Option<&str>) -> Result<&str, ResponseError> {
+    authorization
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or(ResponseError::MissingToken)
+}
+
+async fn handle_runs(runner: Runner) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&runner.runs()))
+}
+
+async fn handle_run(id: u64, runner: Runner) -> Result<impl Reply, Rejection> {
+    let run = runner.get_run(id).ok_or(ResponseError::NotFound("run"))?;
+    Ok(warp::reply::json(&run))
+}
+
+async fn handle_artifact(id: u64, name: String, runner: Runner) -> Result<impl Reply, Rejection> {
+    let name = decode_segment(&name)?;
+    let path = runner
+        .artifact_path(id, &name)
+        .ok_or(ResponseError::NotFound("artifact"))?;
+
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let data = tokio::fs::read(&path)
+        .await
+        .map_err(ResponseError::ReadArtifact)?;
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(data, "content-type", "application/gzip"),
+        "content-disposition",
+        format!("attachment; filename=\"{file_name}\""),
+    ))
+}
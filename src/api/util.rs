@@ -1,6 +1,16 @@
-use super::error::Result;
+use super::error::{ResponseError, Result};
+use percent_encoding::percent_decode_str;
 use std::net::IpAddr;
 
+/// Decodes a percent-encoded path segment, so that names containing a
+/// slash can be passed as `%2F`.
+pub fn decode_segment(segment: &str) -> Result<String, ResponseError> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .map(|v| v.into_owned())
+        .map_err(|_| ResponseError::InvalidPathSegment(segment.into()))
+}
+
 pub fn str_to_ip(addr: &str) -> Result<IpAddr> {
     let split = addr
         .splitn(4, '.')
@@ -9,3 +19,19 @@ pub fn str_to_ip(addr: &str) -> Result<IpAddr> {
 
     Ok([split[0], split[1], split[2], split[3]].into())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_segments() {
+        assert_eq!("production", decode_segment("production").unwrap());
+        assert_eq!(
+            "preview-feature/x",
+            decode_segment("preview-feature%2Fx").unwrap()
+        );
+        assert_eq!("a b", decode_segment("a%20b").unwrap());
+        assert!(decode_segment("%FF").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Writes the given entries into a tar.gz archive at `target`. Each entry
+/// is a pair of the absolute path of a file or directory and the path it
+/// is stored under in the archive.
+pub fn pack(target: &Path, entries: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    let mut archive = tar::Builder::new(GzEncoder::new(
+        File::create(target)?,
+        Compression::default(),
+    ));
+
+    for (path, name) in entries {
+        if path.is_dir() {
+            archive.append_dir_all(name, path)?;
+        } else {
+            archive.append_path_with_name(path, name)?;
+        }
+    }
+
+    archive.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+/// Extracts the tar.gz archive at `source` into the directory `dir`.
+pub fn unpack(source: &Path, dir: &Path) -> io::Result<()> {
+    tar::Archive::new(GzDecoder::new(File::open(source)?)).unpack(dir)
+}
@@ -0,0 +1,22 @@
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed accessing artifact store: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid artifact pattern: {0}")]
+    InvalidPattern(#[from] glob::PatternError),
+
+    #[error("failed reading artifact path: {0}")]
+    Glob(#[from] glob::GlobError),
+
+    #[error("invalid job id {0:?} for artifacts")]
+    InvalidJobId(String),
+
+    #[error("artifact path {0} is outside of the checkout directory")]
+    OutsideOfCheckout(String),
+
+    #[error("artifact task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
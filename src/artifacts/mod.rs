@@ -0,0 +1,209 @@
+pub mod error;
+
+use crate::archive;
+use error::{Error, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const ARCHIVE_EXTENSION: &str = "tar.gz";
+
+/// Characters of job IDs which are percent-encoded in archive names.
+const ENCODED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'_').remove(b'-').remove(b'.');
+
+/// Returns the file name of the archive of the given job. Characters which
+/// are not safe in file names, like slashes, are percent-encoded.
+fn archive_name(job_id: &str) -> Option<String> {
+    if job_id.is_empty() {
+        return None;
+    }
+    let name = utf8_percent_encode(job_id, ENCODED);
+    Some(format!("{name}.{ARCHIVE_EXTENSION}"))
+}
+
+/// Stores the artifacts collected from jobs as tar.gz archives in the
+/// layout `<dir>/<run_id>/<job_id>.tar.gz`.
+#[derive(Clone)]
+pub struct ArtifactStore {
+    dir: PathBuf,
+    retention: Option<Duration>,
+}
+
+impl ArtifactStore {
+    pub fn new(dir: impl Into<PathBuf>, retention: Option<Duration>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, retention })
+    }
+
+    /// Archives all files and directories in `dir` matching one of the given
+    /// glob patterns. Returns the path and size of the archive or `None` if
+    /// nothing matched.
+    pub async fn collect(
+        &self,
+        run_id: u64,
+        job_id: &str,
+        dir: &Path,
+        patterns: &[String],
+    ) -> Result<Option<(PathBuf, u64)>> {
+        let store = self.clone();
+        let job_id = job_id.to_string();
+        let dir = dir.to_path_buf();
+        let patterns = patterns.to_vec();
+        tokio::task::spawn_blocking(move || {
+            store.collect_blocking(run_id, &job_id, &dir, &patterns)
+        })
+        .await?
+    }
+
+    fn collect_blocking(
+        &self,
+        run_id: u64,
+        job_id: &str,
+        dir: &Path,
+        patterns: &[String],
+    ) -> Result<Option<(PathBuf, u64)>> {
+        let dir = dir.canonicalize()?;
+
+        let mut matches = vec![];
+        for pattern in patterns {
+            let pattern = dir.join(pattern);
+            for path in glob::glob(&pattern.to_string_lossy())? {
+                let path = path?.canonicalize()?;
+                let Ok(rel) = path.strip_prefix(&dir) else {
+                    return Err(Error::OutsideOfCheckout(path.to_string_lossy().into()));
+                };
+                if !rel.as_os_str().is_empty() && !matches.iter().any(|(_, r)| r == rel) {
+                    matches.push((path.clone(), rel.to_path_buf()));
+                }
+            }
+        }
+
+        if matches.is_empty() {
+            return Ok(None);
+        }
+
+        let name = archive_name(job_id).ok_or_else(|| Error::InvalidJobId(job_id.into()))?;
+
+        let run_dir = self.dir.join(run_id.to_string());
+        fs::create_dir_all(&run_dir)?;
+
+        let archive_path = run_dir.join(name);
+        archive::pack(&archive_path, &matches)?;
+
+        let size = fs::metadata(&archive_path)?.len();
+        Ok(Some((archive_path, size)))
+    }
+
+    /// Returns the path to the archive of the given job in the given run if
+    /// it exists. The name is the job ID, optionally with the archive
+    /// extension.
+    pub fn path(&self, run_id: u64, name: &str) -> Option<PathBuf> {
+        let job_id = name
+            .strip_suffix(&format!(".{ARCHIVE_EXTENSION}"))
+            .unwrap_or(name);
+
+        let path = self
+            .dir
+            .join(run_id.to_string())
+            .join(archive_name(job_id)?);
+
+        path.exists().then_some(path)
+    }
+
+    /// Removes the artifacts of all runs which are older than the retention
+    /// period. Returns the IDs of the runs whose artifacts were removed.
+    pub async fn cleanup(&self) -> Result<Vec<u64>> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || store.cleanup_blocking()).await?
+    }
+
+    fn cleanup_blocking(&self) -> Result<Vec<u64>> {
+        let Some(retention) = self.retention else {
+            return Ok(vec![]);
+        };
+
+        let mut removed = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Some(run_id) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+
+            let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if age > retention {
+                fs::remove_dir_all(entry.path())?;
+                removed.push(run_id);
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn new_store(retention: Option<Duration>) -> (TempDir, ArtifactStore) {
+        let dir = TempDir::new().unwrap();
+        let store = ArtifactStore::new(dir.path().join("artifacts"), retention).unwrap();
+        (dir, store)
+    }
+
+    async fn collect(store: &ArtifactStore, run_id: u64, job_id: &str) -> PathBuf {
+        let checkout = TempDir::new().unwrap();
+        fs::write(checkout.child("out.txt"), "out").unwrap();
+        let (path, _) = store
+            .collect(run_id, job_id, checkout.path(), &["out.txt".into()])
+            .await
+            .unwrap()
+            .unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn paths() {
+        let (_dir, store) = new_store(None);
+
+        let build = collect(&store, 1, "build").await;
+        assert_eq!(Some(build.clone()), store.path(1, "build"));
+        assert_eq!(Some(build), store.path(1, "build.tar.gz"));
+
+        let linux = collect(&store, 1, "build.linux").await;
+        assert_eq!(Some(linux), store.path(1, "build.linux"));
+
+        let escaped = collect(&store, 1, "../x").await;
+        assert_eq!(store.dir.join("1"), escaped.parent().unwrap());
+        assert_eq!(Some(escaped), store.path(1, "../x"));
+
+        assert_eq!(None, store.path(1, "missing"));
+        assert_eq!(None, store.path(2, "build"));
+        assert_eq!(None, store.path(1, ""));
+        assert_eq!(None, store.path(1, ".tar.gz"));
+    }
+
+    #[tokio::test]
+    async fn cleanup() {
+        let (_dir, store) = new_store(None);
+        collect(&store, 1, "build").await;
+        assert!(store.cleanup().await.unwrap().is_empty());
+        assert!(store.path(1, "build").is_some());
+
+        let (_dir, store) = new_store(Some(Duration::from_millis(10)));
+        collect(&store, 1, "build").await;
+        collect(&store, 2, "build").await;
+        fs::create_dir(store.dir.join("other")).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut removed = store.cleanup().await.unwrap();
+        removed.sort();
+        assert_eq!(vec![1, 2], removed);
+        assert_eq!(None, store.path(1, "build"));
+        assert!(store.dir.join("other").exists());
+    }
+}
@@ -0,0 +1,13 @@
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed accessing cache store: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("cache path {0} must be relative to the repository root")]
+    InvalidPath(String),
+
+    #[error("cache task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
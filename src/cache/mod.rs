@@ -0,0 +1,259 @@
+pub mod error;
+
+use crate::archive;
+use error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+const ENTRY_EXTENSION: &str = "tar.gz";
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Stores directories of jobs between runs as tar.gz archives identified
+/// by a key, in the layout `<dir>/<remote hash>/<key hash>.tar.gz`. Entries
+/// are scoped by repository, so that repositories can not restore caches
+/// saved by others. When the total size of all entries exceeds the maximum
+/// size, the least recently used entries are evicted.
+#[derive(Clone)]
+pub struct CacheStore {
+    dir: PathBuf,
+    max_size: Option<u64>,
+}
+
+impl CacheStore {
+    pub fn new(dir: impl Into<PathBuf>, max_size: Option<u64>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_size })
+    }
+
+    /// Extracts the entry of the repository with the given key into `dir`.
+    /// Returns `false` if there is no entry for the key.
+    pub async fn restore(&self, remote: &str, key: &str, dir: &Path) -> Result<bool> {
+        let path = self.entry_path(remote, key);
+        let dir = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || Self::restore_entry(&path, &dir)).await?
+    }
+
+    fn restore_entry(path: &Path, dir: &Path) -> Result<bool> {
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        archive::unpack(path, dir)?;
+
+        // Touch the entry so that it is considered recently used on eviction.
+        File::options()
+            .append(true)
+            .open(path)?
+            .set_modified(SystemTime::now())?;
+
+        Ok(true)
+    }
+
+    /// Archives the given paths, relative to `dir`, as entry of the
+    /// repository with the given key. Returns `false` if none of the paths
+    /// exist.
+    pub async fn save(
+        &self,
+        remote: &str,
+        key: &str,
+        dir: &Path,
+        paths: &[String],
+    ) -> Result<bool> {
+        let store = self.clone();
+        let path = self.entry_path(remote, key);
+        let dir = dir.to_path_buf();
+        let paths = paths.to_vec();
+        tokio::task::spawn_blocking(move || store.save_entry(&path, &dir, &paths)).await?
+    }
+
+    fn save_entry(&self, path: &Path, dir: &Path, paths: &[String]) -> Result<bool> {
+        let mut entries = vec![];
+        for path in paths {
+            let rel = Path::new(path);
+            if rel
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(Error::InvalidPath(path.clone()));
+            }
+
+            let full = dir.join(rel);
+            if full.exists() {
+                entries.push((full, rel.to_path_buf()));
+            }
+        }
+
+        if entries.is_empty() {
+            return Ok(false);
+        }
+
+        // Write to a temporary file first so that concurrent restores never
+        // see a partially written entry.
+        let tmp_path = self.dir.join(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(err) = archive::pack(&tmp_path, &entries) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        self.evict()?;
+
+        Ok(true)
+    }
+
+    /// Removes the least recently used entries until the total size of all
+    /// entries is below the maximum size.
+    fn evict(&self) -> Result<()> {
+        let Some(max_size) = self.max_size else {
+            return Ok(());
+        };
+
+        let mut entries = vec![];
+        for repo_dir in fs::read_dir(&self.dir)? {
+            let repo_dir = repo_dir?;
+            if !repo_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(repo_dir.path())? {
+                let entry = entry?;
+                if !entry
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(ENTRY_EXTENSION)
+                {
+                    continue;
+                }
+                let meta = entry.metadata()?;
+                entries.push((meta.modified()?, meta.len(), entry.path()));
+            }
+        }
+
+        entries.sort_by_key(|(modified, _, _)| *modified);
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in entries {
+            if total <= max_size {
+                break;
+            }
+            log::debug!("Evicting cache entry {}", path.display());
+            fs::remove_file(path)?;
+            total -= size;
+        }
+
+        Ok(())
+    }
+
+    fn entry_path(&self, remote: &str, key: &str) -> PathBuf {
+        let remote = format!("{:x}", Sha256::digest(remote));
+        let key = format!("{:x}", Sha256::digest(key));
+        self.dir
+            .join(&remote[..16])
+            .join(format!("{key}.{ENTRY_EXTENSION}"))
+    }
+}
+
+/// Returns a SHA-256 hash over the names and contents of the given files,
+/// relative to `dir`. Files which do not exist only contribute their name.
+pub fn hash_files(dir: &Path, files: &[String]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.as_bytes());
+        let path = dir.join(file);
+        if path.is_file() {
+            hasher.update(fs::read(path)?);
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use temp_dir::TempDir;
+
+    const REMOTE: &str = "/repos/app.git";
+
+    async fn save(store: &CacheStore, remote: &str, key: &str, content: &str) {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.child("deps"), content).unwrap();
+        assert!(store
+            .save(remote, key, dir.path(), &["deps".into()])
+            .await
+            .unwrap());
+        // Make sure that modification times differ between entries.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    async fn restore(store: &CacheStore, remote: &str, key: &str) -> Option<String> {
+        let dir = TempDir::new().unwrap();
+        store
+            .restore(remote, key, dir.path())
+            .await
+            .unwrap()
+            .then(|| fs::read_to_string(dir.child("deps")).unwrap())
+    }
+
+    #[tokio::test]
+    async fn scoped_entries() {
+        let dir = TempDir::new().unwrap();
+        let store = CacheStore::new(dir.path(), None).unwrap();
+
+        save(&store, REMOTE, "a/b", "slash").await;
+        save(&store, REMOTE, "a_b", "underscore").await;
+        save(&store, "/repos/other.git", "a/b", "other").await;
+
+        assert_eq!(Some("slash".into()), restore(&store, REMOTE, "a/b").await);
+        assert_eq!(
+            Some("underscore".into()),
+            restore(&store, REMOTE, "a_b").await
+        );
+        assert_eq!(
+            Some("other".into()),
+            restore(&store, "/repos/other.git", "a/b").await
+        );
+        assert_eq!(None, restore(&store, "/repos/other.git", "a_b").await);
+        assert_eq!(None, restore(&store, REMOTE, "../a_b").await);
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let dir = TempDir::new().unwrap();
+        let store = CacheStore::new(dir.path(), None).unwrap();
+        save(&store, REMOTE, "size", "content").await;
+        let size = fs::metadata(store.entry_path(REMOTE, "size"))
+            .unwrap()
+            .len();
+        fs::remove_file(store.entry_path(REMOTE, "size")).unwrap();
+
+        // Room for three entries.
+        let store = CacheStore::new(dir.path(), Some(size * 3 + size / 2)).unwrap();
+        save(&store, REMOTE, "a", "content").await;
+        save(&store, REMOTE, "b", "content").await;
+        save(&store, "/repos/other.git", "c", "content").await;
+
+        // Restoring marks the entry as recently used.
+        assert!(restore(&store, REMOTE, "a").await.is_some());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        save(&store, REMOTE, "d", "content").await;
+        assert!(restore(&store, REMOTE, "a").await.is_some());
+        assert!(restore(&store, REMOTE, "b").await.is_none());
+        assert!(restore(&store, "/repos/other.git", "c").await.is_some());
+        assert!(restore(&store, REMOTE, "d").await.is_some());
+    }
+}
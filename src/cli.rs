@@ -0,0 +1,150 @@
+use crate::{
+    config::Config,
+    secrets::{self, encryption::Key, SecretManager},
+};
+use serde_yaml::{Mapping, Value};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const USAGE: &str = "usage: minicd secrets <get <key> | set <key> [value] | edit>";
+
+/// Runs the command given by the command line arguments.
+pub fn run(cfg: &Config, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+    let ["secrets", args @ ..] = args.as_slice() else {
+        return Err(USAGE.into());
+    };
+
+    let path = cfg
+        .secrets_file
+        .as_ref()
+        .ok_or("no secrets_file has been configured")?;
+    let key = Key::load(cfg.secrets_key_file.as_deref())?;
+    let encrypt = encrypts(Path::new(path), key.as_ref())?;
+    let key = key.as_ref();
+    // Keys are only used for writing if the file is meant to be encrypted.
+    let write_key = key.filter(|_| encrypt);
+
+    match args {
+        ["get", name] => {
+            let secrets = SecretManager::new(path, key)?;
+            let value = secrets
+                .get(name)
+                .ok_or_else(|| format!("secret {name} does not exist"))?;
+            println!("{value}");
+        }
+        ["set", name, value @ ..] => {
+            let value = match value {
+                [value] => value.to_string(),
+                [] => {
+                    let mut value = String::new();
+                    io::stdin().read_to_string(&mut value)?;
+                    value.trim_end_matches('\n').to_string()
+                }
+                _ => return Err(USAGE.into()),
+            };
+
+            let mut secrets = match Path::new(path).exists() {
+                true => serde_yaml::from_slice(&secrets::read_file(path, key)?)?,
+                false => Value::Mapping(Mapping::new()),
+            };
+            set(&mut secrets, name, value)?;
+
+            let data = serde_yaml::to_string(&secrets)?;
+            secrets::write_file(path, data.as_bytes(), write_key)?;
+        }
+        ["edit"] => {
+            let data = match Path::new(path).exists() {
+                true => secrets::read_file(path, key)?,
+                false => vec![],
+            };
+            let data = edit(&data)?;
+            serde_yaml::from_slice::<secrets::Value>(&data)?;
+            secrets::write_file(path, &data, write_key)?;
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
+
+/// Returns whether the secrets file is or shall be encrypted, which is the
+/// case if it already is encrypted or has the `.age` extension.
+fn encrypts(path: &Path, key: Option<&Key>) -> Result<bool, Box<dyn Error>> {
+    let encrypted = path.extension().is_some_and(|ext| ext == "age")
+        || (path.exists() && secrets::encryption::is_encrypted(&fs::read(path)?));
+    if encrypted && key.is_none() {
+        return Err(secrets::error::Error::MissingKey.into());
+    }
+    Ok(encrypted)
+}
+
+/// Sets the secret with the given dotted name, creating missing sections.
+fn set(secrets: &mut Value, name: &str, value: String) -> secrets::error::Result<()> {
+    let mut target = secrets;
+    for part in name.split('.') {
+        let Value::Mapping(map) = target else {
+            return Err(secrets::error::Error::NotASection(name.into()));
+        };
+        target = map
+            .entry(Value::String(part.into()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+    }
+    // Secrets tagged with `!file` keep their tag.
+    match target {
+        Value::Tagged(tagged) => tagged.value = Value::String(value),
+        target => *target = Value::String(value),
+    }
+    Ok(())
+}
+
+/// Opens the given content in the editor set in `$EDITOR` and returns the
+/// edited content. The content is only placed in a memory backed directory
+/// so that the plaintext is never written to disk.
+fn edit(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain([PathBuf::from("/dev/shm")])
+        .find(|dir| dir.is_dir())
+        .ok_or("no memory backed directory ($XDG_RUNTIME_DIR or /dev/shm) is available")?;
+
+    let file = TempFile(dir.join(format!("minicd-secrets-{}.yaml", std::process::id())));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&file.0)?.write_all(data)?;
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".into());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&file.0)
+        .status()?;
+    if !status.success() {
+        return Err(format!("editor exited with {status}").into());
+    }
+
+    Ok(fs::read(&file.0)?)
+}
+
+/// Removes the file at the contained path when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -3,16 +3,91 @@ use figment::{
     Figment,
 };
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub port: u16,
     pub address: Option<String>,
+    pub public_url: Option<String>,
     pub repo_dir: Option<PathBuf>,
     pub index_interval_secs: Option<u64>,
     pub secrets_file: Option<String>,
+    pub secrets_key_file: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub mirror_repos: Option<bool>,
+    pub environment_ttl_secs: Option<u64>,
+    pub watch_config: Option<bool>,
     pub email: Option<EmailConfig>,
+    pub artifacts: Option<ArtifactsConfig>,
+    pub cache: Option<CacheConfig>,
+    #[serde(default)]
+    pub secret_providers: Vec<SecretProviderConfig>,
+    #[serde(default)]
+    pub secret_policies: Vec<SecretPolicy>,
+    #[serde(default)]
+    pub approval_policies: Vec<ApprovalPolicy>,
+    /// Tokens of the users allowed to modify environments through the API,
+    /// by user name.
+    #[serde(default)]
+    pub api_tokens: HashMap<String, String>,
+}
+
+/// A source of secrets. Secrets of providers listed later take precedence.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SecretProviderConfig {
+    Yaml {
+        path: PathBuf,
+    },
+    Env {
+        prefix: String,
+    },
+    Directory {
+        /// Defaults to `$CREDENTIALS_DIRECTORY` if not set.
+        path: Option<PathBuf>,
+    },
+    Vault {
+        address: String,
+        /// Defaults to the `VAULT_TOKEN` environment variable if not set.
+        token: Option<String>,
+        mount: Option<String>,
+        path: String,
+        kv_version: Option<u8>,
+    },
+}
+
+/// Grants repositories matching any of the `repos` globs access to the
+/// given secret subtrees when pushing to a reference matching any of the
+/// `refs` globs.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SecretPolicy {
+    pub repos: Vec<String>,
+    pub refs: Option<Vec<String>>,
+    pub secrets: Vec<String>,
+}
+
+/// Requires jobs deploying to an environment matching any of the
+/// `environments` globs in repositories matching any of the `repos` globs to
+/// be approved by one of the `approvers`, regardless of their definition.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApprovalPolicy {
+    pub repos: Vec<String>,
+    pub environments: Vec<String>,
+    pub approvers: Vec<String>,
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ArtifactsConfig {
+    pub dir: Option<PathBuf>,
+    pub retention_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CacheConfig {
+    pub dir: Option<PathBuf>,
+    pub max_size_mb: Option<u64>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -23,14 +98,112 @@ pub struct EmailConfig {
     pub from_address: String,
 }
 
+/// The config files in the order they are merged.
+const FILES: [&str; 4] = [
+    "minicd.toml",
+    "minicd.yaml",
+    "/etc/minicd/config.toml",
+    "/etc/minicd/config.yaml",
+];
+
 impl Config {
-    pub fn parse() -> Result<Self, figment::Error> {
-        Figment::new()
-            .merge(Toml::file("minicd.toml"))
-            .merge(Yaml::file("minicd.yaml"))
-            .merge(Toml::file("/etc/minicd/config.toml"))
-            .merge(Yaml::file("/etc/minicd/config.yaml"))
+    pub fn parse() -> Result<Self, Box<figment::Error>> {
+        FILES
+            .iter()
+            .fold(Figment::new(), |figment, file| {
+                if file.ends_with(".toml") {
+                    figment.merge(Toml::file(file))
+                } else {
+                    figment.merge(Yaml::file(file))
+                }
+            })
             .merge(Env::prefixed("MINICD_"))
             .extract()
+            .map_err(Box::new)
+    }
+
+    /// Returns a copy of the config with all tokens and passwords replaced,
+    /// so that it can be logged.
+    pub fn redacted(&self) -> Self {
+        const REDACTED: &str = "***";
+
+        let mut cfg = self.clone();
+        for token in cfg.api_tokens.values_mut() {
+            *token = REDACTED.into();
+        }
+        for provider in &mut cfg.secret_providers {
+            if let SecretProviderConfig::Vault {
+                token: Some(token), ..
+            } = provider
+            {
+                *token = REDACTED.into();
+            }
+        }
+        if let Some(email) = &mut cfg.email {
+            email.password = REDACTED.into();
+        }
+        cfg
+    }
+
+    /// The files which affect the settings that can be reloaded.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = FILES.iter().map(PathBuf::from).collect();
+        files.extend(self.secrets_file.iter().map(PathBuf::from));
+        files.extend(self.secrets_key_file.iter().cloned());
+        for provider in &self.secret_providers {
+            match provider {
+                SecretProviderConfig::Yaml { path } => files.push(path.clone()),
+                SecretProviderConfig::Directory { path: Some(path) } => files.push(path.clone()),
+                _ => {}
+            }
+        }
+        files
+    }
+
+    /// The prefixes of the variables read by the env secret providers.
+    pub fn env_secret_prefixes(&self) -> Vec<&str> {
+        self.secret_providers
+            .iter()
+            .filter_map(|p| match p {
+                SecretProviderConfig::Env { prefix } if !prefix.is_empty() => Some(prefix.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let cfg: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+port = 8080
+[api_tokens]
+alice = "alice-token"
+[email]
+smtp_server = "smtp.example.com"
+username = "minicd"
+password = "mail-password"
+from_address = "minicd@example.com"
+[[secret_providers]]
+type = "vault"
+address = "https://vault.example.com"
+token = "vault-token"
+path = "minicd"
+"#,
+            ))
+            .extract()
+            .unwrap();
+
+        let dump = format!("{:?}", cfg.redacted());
+        assert!(dump.contains("alice"));
+        assert!(dump.contains("minicd@example.com"));
+        for secret in ["alice-token", "mail-password", "vault-token"] {
+            assert!(!dump.contains(secret), "{secret} is not redacted");
+        }
     }
 }
@@ -1,6 +1,11 @@
+use crate::git;
 use regex::Regex;
 use serde::Deserialize;
-use std::{collections::HashMap, fmt, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
 
 #[derive(thiserror::Error, Debug)]
 #[error("invalid reference format")]
@@ -9,20 +14,117 @@ pub struct RefParseError;
 #[derive(Deserialize)]
 pub struct Definition {
     pub name: String,
+    pub checkout: Option<Checkout>,
+    pub env: Option<HashMap<String, String>>,
+    pub strict_placeholders: Option<bool>,
     pub jobs: HashMap<String, Job>,
 }
 
+#[derive(Deserialize, Clone, Default)]
+pub struct Checkout {
+    pub depth: Option<u32>,
+    #[serde(default)]
+    pub submodules: Submodules,
+    #[serde(default)]
+    pub lfs: bool,
+    #[serde(default)]
+    pub sparse: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum Submodules {
+    Enabled(bool),
+    Mode(SubmoduleMode),
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SubmoduleMode {
+    Recursive,
+}
+
+impl Default for Submodules {
+    fn default() -> Self {
+        Self::Enabled(false)
+    }
+}
+
+impl From<Checkout> for git::CheckoutOptions {
+    fn from(value: Checkout) -> Self {
+        Self {
+            depth: value.depth,
+            submodules: match value.submodules {
+                Submodules::Enabled(false) => git::Submodules::Disabled,
+                Submodules::Enabled(true) => git::Submodules::Enabled,
+                Submodules::Mode(SubmoduleMode::Recursive) => git::Submodules::Recursive,
+            },
+            lfs: value.lfs,
+            sparse: value.sparse,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Job {
-    #[serde(with = "serde_yaml::with::singleton_map")]
-    pub on: Option<Ref>,
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub on: Option<Trigger>,
     pub notify: Option<Vec<Notify>>,
     pub shell: Option<ValueOrList<String>>,
     #[serde(rename = "await")]
     pub await_result: Option<bool>,
+    pub environment: Option<Environment>,
+    pub needs: Option<Vec<String>>,
+    pub approval: Option<Approval>,
+    pub artifacts: Option<Vec<String>>,
+    pub cache: Option<ValueOrList<Cache>>,
+    pub env: Option<HashMap<String, String>>,
+    pub secrets: Option<Vec<JobSecret>>,
     pub run: String,
 }
 
+/// A secret passed to a job, either as `SECRETS_*` variable or, for keys
+/// like SSH keys which are needed as files, as path to a temporary file in
+/// the `SECRETS_*_FILE` variable.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum JobSecret {
+    Key(String),
+    Typed {
+        key: String,
+        #[serde(rename = "type", default)]
+        typ: SecretType,
+    },
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretType {
+    #[default]
+    Env,
+    File,
+}
+
+#[derive(Deserialize)]
+pub struct Cache {
+    pub key: String,
+    pub hash_files: Option<Vec<String>>,
+    pub paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Environment {
+    pub name: String,
+    pub on_stop: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Approval {
+    /// Names of users configured in the `api_tokens` of minicd.
+    pub approvers: Vec<String>,
+    pub timeout_secs: Option<u64>,
+}
+
 #[derive(Deserialize)]
 pub struct Notify {
     pub to: Vec<NotifyTarget>,
@@ -43,10 +145,25 @@ pub enum Event {
     #[serde(rename = "start")]
     Start,
 
+    #[serde(rename = "waiting")]
+    Waiting,
+
     #[serde(rename = "all")]
     All,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub enum Trigger {
+    #[serde(rename = "branch")]
+    Branch(String),
+
+    #[serde(rename = "tag")]
+    Tag(String),
+
+    #[serde(rename = "delete")]
+    Delete(Ref),
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub enum Ref {
     #[serde(rename = "branch")]
@@ -74,18 +191,56 @@ pub enum NotifyTarget {
         method: Option<String>,
         headers: Option<HashMap<String, String>>,
     },
+    #[serde(rename = "slack")]
+    Slack { url: String },
+    #[serde(rename = "discord")]
+    Discord { url: String },
+    #[serde(rename = "matrix")]
+    Matrix {
+        homeserver: String,
+        room: String,
+        access_token: String,
+    },
+    #[serde(rename = "telegram")]
+    Telegram {
+        token: String,
+        chat_id: String,
+        /// Defaults to the official bot API.
+        api_url: Option<String>,
+    },
+    #[serde(rename = "ntfy")]
+    Ntfy {
+        /// Defaults to `https://ntfy.sh`.
+        server: Option<String>,
+        topic: String,
+        token: Option<String>,
+    },
+    #[serde(rename = "gotify")]
+    Gotify { url: String, token: String },
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum JobState {
     Start,
+    Waiting,
     Success,
     Failure,
+    Skipped,
 }
 
 impl Definition {
     pub fn parse(r: &[u8]) -> Result<Self, serde_yaml::Error> {
         serde_yaml::from_slice(r)
     }
+
+    /// Returns the ids of the jobs which stop environments. They are only
+    /// run when an environment is stopped and never on pushes.
+    pub fn stop_jobs(&self) -> HashSet<String> {
+        self.jobs
+            .values()
+            .filter_map(|job| job.environment.as_ref()?.on_stop.clone())
+            .collect()
+    }
 }
 
 impl Job {
@@ -102,6 +257,34 @@ impl Job {
                 .collect()
         })
     }
+
+    pub fn needs(&self) -> impl Iterator<Item = &String> {
+        self.needs.iter().flatten()
+    }
+}
+
+impl JobSecret {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Key(key) | Self::Typed { key, .. } => key,
+        }
+    }
+
+    pub fn typ(&self) -> SecretType {
+        match self {
+            Self::Key(_) => SecretType::Env,
+            Self::Typed { typ, .. } => *typ,
+        }
+    }
+}
+
+impl<T> ValueOrList<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            Self::Value(v) => std::slice::from_ref(v),
+            Self::List(v) => v,
+        }
+    }
 }
 
 impl Event {
@@ -111,6 +294,7 @@ impl Event {
             Event::Success if matches!(e, JobState::Success) => true,
             Event::Failure if matches!(e, JobState::Failure) => true,
             Event::Start if matches!(e, JobState::Start) => true,
+            Event::Waiting if matches!(e, JobState::Waiting) => true,
             Event::Finish if matches!(e, JobState::Success) || matches!(e, JobState::Failure) => {
                 true
             }
@@ -139,6 +323,23 @@ impl FromStr for Ref {
     }
 }
 
+impl Ref {
+    /// Returns the type of the reference, either `branch` or `tag`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Branch(_) => "branch",
+            Self::Tag(_) => "tag",
+        }
+    }
+
+    /// Returns the short name of the reference like `main` or `v1.0.0`.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Branch(v) | Self::Tag(v) => v,
+        }
+    }
+}
+
 impl fmt::Display for Ref {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -148,6 +349,19 @@ impl fmt::Display for Ref {
     }
 }
 
+impl Trigger {
+    /// Returns true when the trigger matches the given pushed reference.
+    /// `deleted` must be set when the reference has been removed by the push.
+    pub fn matches(&self, rf: &Ref, deleted: bool) -> bool {
+        match (self, rf) {
+            (Trigger::Branch(rx), Ref::Branch(ref rf)) if !deleted => rx_match(rx, rf),
+            (Trigger::Tag(rx), Ref::Tag(ref rf)) if !deleted => rx_match(rx, rf),
+            (Trigger::Delete(pattern), rf) if deleted => pattern.matches(rf),
+            _ => false,
+        }
+    }
+}
+
 impl Ref {
     pub fn matches(&self, rf: &Ref) -> bool {
         match (self, rf) {
@@ -165,3 +379,134 @@ fn rx_match(rx: &str, rf: &str) -> bool {
 
     rx.is_match(rf)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_checkout() {
+        let def = Definition::parse(
+            br#"
+name: test
+checkout:
+  depth: 1
+  submodules: recursive
+  sparse:
+    - src
+jobs: {}
+"#,
+        )
+        .unwrap();
+
+        let options: git::CheckoutOptions = def.checkout.unwrap().into();
+        assert_eq!(Some(1), options.depth);
+        assert_eq!(git::Submodules::Recursive, options.submodules);
+        assert!(!options.lfs);
+        assert_eq!(vec!["src".to_string()], options.sparse);
+
+        let def =
+            Definition::parse(b"name: test\ncheckout:\n  submodules: true\njobs: {}").unwrap();
+        let options: git::CheckoutOptions = def.checkout.unwrap().into();
+        assert_eq!(git::Submodules::Enabled, options.submodules);
+    }
+
+    #[test]
+    fn stop_jobs() {
+        let def = Definition::parse(
+            br#"
+name: test
+jobs:
+  deploy:
+    environment:
+      name: preview
+      on_stop: teardown
+    run: ./deploy.sh
+  teardown:
+    run: ./teardown.sh
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(HashSet::from(["teardown".to_string()]), def.stop_jobs());
+    }
+
+    #[test]
+    fn parse_job_secrets() {
+        let def = Definition::parse(
+            br#"
+name: test
+jobs:
+  deploy:
+    secrets:
+      - docker
+      - key: deploy.ssh_key
+        type: file
+    run: ./deploy.sh
+"#,
+        )
+        .unwrap();
+
+        let secrets: Vec<_> = def.jobs["deploy"]
+            .secrets
+            .iter()
+            .flatten()
+            .map(|s| (s.key(), s.typ()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("docker", SecretType::Env),
+                ("deploy.ssh_key", SecretType::File)
+            ],
+            secrets
+        );
+    }
+
+    #[test]
+    fn parse_trigger() {
+        let def = Definition::parse(
+            br#"
+name: test
+jobs:
+  push:
+    on:
+      branch: main
+    run: echo push
+  teardown:
+    on:
+      delete:
+        branch: feature/.*
+    run: echo delete
+"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            def.jobs["push"].on,
+            Some(Trigger::Branch(ref v)) if v == "main"
+        ));
+        assert!(matches!(
+            def.jobs["teardown"].on,
+            Some(Trigger::Delete(Ref::Branch(ref v))) if v == "feature/.*"
+        ));
+    }
+
+    #[test]
+    fn trigger_matches() {
+        let feature = Ref::Branch("feature/foo".into());
+        let tag = Ref::Tag("v1.0.0".into());
+
+        let push = Trigger::Branch("feature/.*".into());
+        assert!(push.matches(&feature, false));
+        assert!(!push.matches(&feature, true));
+        assert!(!push.matches(&tag, false));
+
+        let delete = Trigger::Delete(Ref::Branch("feature/.*".into()));
+        assert!(delete.matches(&feature, true));
+        assert!(!delete.matches(&feature, false));
+        assert!(!delete.matches(&tag, true));
+
+        let delete_tag = Trigger::Delete(Ref::Tag(".*".into()));
+        assert!(delete_tag.matches(&tag, true));
+    }
+}
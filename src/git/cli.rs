@@ -0,0 +1,76 @@
+use super::{cmd, error::Result, CommitInfo, Signature};
+
+/// Fields of a commit separated by unit separators. Each record ends with a
+/// record separator, as the message may contain any other character.
+const LOG_FORMAT: &str = "--format=%H%x1f%an%x1f%ae%x1f%cn%x1f%ce%x1f%ct%x1f%B%x1e";
+
+pub fn rev_parse(dir: &str, spec: &str) -> Result<String> {
+    // Peeling makes sure that the commit actually exists, as full hashes
+    // are returned as they are otherwise.
+    let spec = format!("{spec}^{{commit}}");
+    let out = cmd([
+        "-C",
+        dir,
+        "rev-parse",
+        "--verify",
+        "--end-of-options",
+        &spec,
+    ])?;
+    Ok(String::from_utf8(out.stdout)?.trim().to_string())
+}
+
+pub fn is_shallow(dir: &str) -> Result<bool> {
+    let out = cmd(["-C", dir, "rev-parse", "--is-shallow-repository"])?;
+    Ok(String::from_utf8(out.stdout)?.trim() == "true")
+}
+
+pub fn read_file(dir: &str, reference: &str, path: &str) -> Result<Option<Vec<u8>>> {
+    let object = format!("{reference}:{path}");
+    if cmd(["-C", dir, "cat-file", "-e", &object]).is_err() {
+        return Ok(None);
+    }
+    cmd(["-C", dir, "show", &object]).map(|out| Some(out.stdout))
+}
+
+pub fn log(dir: &str, reference: &str, since: Option<&str>, max: usize) -> Result<Vec<CommitInfo>> {
+    let range = match since {
+        Some(since) => format!("{since}..{reference}"),
+        None => reference.to_string(),
+    };
+    let out = cmd([
+        "-C",
+        dir,
+        "log",
+        &format!("--max-count={max}"),
+        LOG_FORMAT,
+        &range,
+        "--",
+    ])?;
+
+    let commits = String::from_utf8(out.stdout)?
+        .split('\x1e')
+        .filter_map(|record| {
+            let fields: Vec<_> = record.trim_start().splitn(7, '\x1f').collect();
+            let [sha, author_name, author_email, committer_name, committer_email, time, message] =
+                fields[..]
+            else {
+                return None;
+            };
+            Some(CommitInfo::new(
+                sha.to_string(),
+                Signature {
+                    name: author_name.to_string(),
+                    email: author_email.to_string(),
+                },
+                Signature {
+                    name: committer_name.to_string(),
+                    email: committer_email.to_string(),
+                },
+                time.parse().unwrap_or_default(),
+                message,
+            ))
+        })
+        .collect();
+
+    Ok(commits)
+}
@@ -14,8 +14,59 @@ pub enum Error {
     #[error("Command failed ({code}): {message}")]
     CommandStatus { code: ExitStatus, message: String },
 
+    #[error("failed cloning {remote} into {dir}: {message}")]
+    Clone {
+        remote: String,
+        dir: String,
+        message: String,
+    },
+
     #[error("failed encoding output to UTF8: {0}")]
     OutputEncode(#[from] FromUtf8Error),
+
+    #[cfg(feature = "gix")]
+    #[error("failed opening repository: {0}")]
+    Open(Box<gix::open::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed parsing revision: {0}")]
+    RevParse(Box<gix::revision::spec::parse::single::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed finding object: {0}")]
+    FindObject(Box<gix::object::find::existing::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed peeling object: {0}")]
+    Peel(Box<gix::object::peel::to_kind::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed reading tree of commit: {0}")]
+    Tree(Box<gix::object::commit::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed decoding object: {0}")]
+    Decode(Box<gix::objs::decode::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed walking history: {0}")]
+    Walk(Box<gix::revision::walk::Error>),
+
+    #[cfg(feature = "gix")]
+    #[error("failed walking history: {0}")]
+    WalkIter(Box<gix::revision::walk::iter::Error>),
+
+    #[error("unknown revision: {0}")]
+    UnknownRevision(String),
+
+    #[error("git task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    #[error("failed fetching submodules: {0}")]
+    Submodule(Box<Error>),
+
+    #[error("failed fetching LFS objects: {0}")]
+    Lfs(Box<Error>),
 }
 
 impl From<Output> for Error {
@@ -26,3 +77,27 @@ impl From<Output> for Error {
         }
     }
 }
+
+/// Implements the conversion of gix errors into the boxed variants.
+#[cfg(feature = "gix")]
+macro_rules! from_gix {
+    ($($variant:ident($typ:ty)),* $(,)?) => {
+        $(impl From<$typ> for Error {
+            fn from(value: $typ) -> Self {
+                Self::$variant(Box::new(value))
+            }
+        })*
+    };
+}
+
+#[cfg(feature = "gix")]
+from_gix!(
+    Open(gix::open::Error),
+    RevParse(gix::revision::spec::parse::single::Error),
+    FindObject(gix::object::find::existing::Error),
+    Peel(gix::object::peel::to_kind::Error),
+    Tree(gix::object::commit::Error),
+    Decode(gix::objs::decode::Error),
+    Walk(gix::revision::walk::Error),
+    WalkIter(gix::revision::walk::iter::Error),
+);
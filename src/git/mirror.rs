@@ -0,0 +1,211 @@
+use super::{blocking, clone_cmd, cmd, error::Result, Repository};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Keeps a bare mirror of each remote so that only new objects have to be
+/// fetched on each push. Checkouts are created as shared clones of the
+/// mirror, which borrow its objects instead of copying them.
+pub struct MirrorStore {
+    dir: PathBuf,
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl MirrorStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            locks: Mutex::default(),
+        }
+    }
+
+    /// Updates the mirror of the given remote and clones it into `dir`
+    /// pointing at the given commit or the default branch. Updates of the
+    /// same mirror are serialized so that concurrent runs can not corrupt it.
+    pub async fn clone(&self, remote: &str, dir: &str, commit: Option<&str>) -> Result<Repository> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(remote.to_string())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        let mirror = self.mirror_path(remote);
+        let (remote, dir, commit) = (
+            remote.to_string(),
+            dir.to_string(),
+            commit.map(String::from),
+        );
+        blocking(move || {
+            update(&remote, &mirror)?;
+            Repository::clone_shared(
+                remote.as_str(),
+                &mirror.to_string_lossy(),
+                &dir,
+                commit.as_deref(),
+            )
+        })
+        .await
+    }
+
+    fn mirror_path(&self, remote: &str) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(remote.as_bytes()));
+        let name: String = Path::new(remote)
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+            .collect();
+        self.dir.join(format!("{name}-{}", &hash[..16]))
+    }
+}
+
+fn update(remote: &str, mirror: &Path) -> Result<()> {
+    let mirror_str = mirror.to_string_lossy();
+    if mirror.exists() {
+        clone_cmd(remote, &mirror_str, || {
+            cmd(["-C", &mirror_str, "fetch", "--quiet", "--prune", "origin"])
+        })?;
+    } else {
+        clone_cmd(remote, &mirror_str, || {
+            cmd(["clone", "--quiet", "--mirror", remote, &mirror_str])
+        })?;
+    }
+
+    // Shared clones of the mirror reference its objects without copying
+    // them, so objects must never be removed by an automatic gc after they
+    // have been pruned. This is also applied to existing mirrors.
+    cmd(["-C", &mirror_str, "config", "gc.auto", "0"])?;
+    cmd(["-C", &mirror_str, "config", "maintenance.auto", "false"])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test::commit;
+
+    fn rev_parse(dir: &str, spec: &str) -> Option<String> {
+        cmd(["-C", dir, "rev-parse", "--verify", "--quiet", spec])
+            .ok()
+            .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+    }
+
+    #[tokio::test]
+    async fn mirror_and_clone() {
+        let tmp = temp_dir::TempDir::new().unwrap();
+        let remote = tmp.child("remote.git");
+        let remote = remote.to_str().unwrap();
+        let work = tmp.child("work");
+        let work = work.to_str().unwrap();
+        cmd(["init", "--quiet", "--bare", remote]).unwrap();
+        cmd(["init", "--quiet", work]).unwrap();
+        cmd(["-C", work, "remote", "add", "origin", remote]).unwrap();
+
+        commit(work, ".minicd", "name: first");
+        let first = rev_parse(work, "HEAD").unwrap();
+        cmd([
+            "-C",
+            work,
+            "push",
+            "--quiet",
+            "origin",
+            "HEAD:refs/heads/main",
+        ])
+        .unwrap();
+        cmd([
+            "-C",
+            work,
+            "push",
+            "--quiet",
+            "origin",
+            "HEAD:refs/heads/feature",
+        ])
+        .unwrap();
+        cmd(["-C", remote, "symbolic-ref", "HEAD", "refs/heads/main"]).unwrap();
+
+        let store = MirrorStore::new(tmp.child("mirrors"));
+        let mirror = store.mirror_path(remote);
+        let mirror_str = mirror.to_str().unwrap();
+
+        // The first clone creates the mirror.
+        let dir = tmp.child("first");
+        let repo = store
+            .clone(remote, dir.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(first, repo.get_ref());
+        assert_eq!(
+            Some(b"name: first".to_vec()),
+            repo.read_file(".minicd").unwrap()
+        );
+        assert_eq!(
+            Some(first.clone()),
+            rev_parse(mirror_str, "refs/heads/feature")
+        );
+        assert_eq!(
+            "0",
+            String::from_utf8(cmd(["-C", mirror_str, "config", "gc.auto"]).unwrap().stdout)
+                .unwrap()
+                .trim()
+        );
+
+        // Further clones only fetch new objects and prune deleted refs.
+        commit(work, ".minicd", "name: second");
+        let second = rev_parse(work, "HEAD").unwrap();
+        cmd([
+            "-C",
+            work,
+            "push",
+            "--quiet",
+            "origin",
+            "HEAD:refs/heads/main",
+        ])
+        .unwrap();
+        cmd([
+            "-C",
+            work,
+            "push",
+            "--quiet",
+            "origin",
+            ":refs/heads/feature",
+        ])
+        .unwrap();
+
+        let dir = tmp.child("second");
+        let repo = store
+            .clone(remote, dir.to_str().unwrap(), Some(&second))
+            .await
+            .unwrap();
+        assert_eq!(second, repo.get_ref());
+        assert_eq!(
+            Some(second.clone()),
+            rev_parse(mirror_str, "refs/heads/main")
+        );
+        assert_eq!(None, rev_parse(mirror_str, "refs/heads/feature"));
+
+        // Clones share the objects of the mirror and point to the remote.
+        let alternates = std::fs::read_to_string(dir.join(".git/objects/info/alternates")).unwrap();
+        assert!(alternates.trim().starts_with(mirror_str));
+        let origin = cmd(["-C", dir.to_str().unwrap(), "remote", "get-url", "origin"]).unwrap();
+        assert_eq!(remote, String::from_utf8(origin.stdout).unwrap().trim());
+
+        // Older commits can be cloned as well.
+        let dir = tmp.child("old");
+        let repo = store
+            .clone(remote, dir.to_str().unwrap(), Some(&first))
+            .await
+            .unwrap();
+        assert_eq!(first, repo.get_ref());
+        assert_eq!(
+            Some(b"name: first".to_vec()),
+            repo.read_file(".minicd").unwrap()
+        );
+    }
+}
@@ -1,33 +1,262 @@
-#![allow(unused)]
-
+// The CLI read helpers are only used by tests when gix is enabled.
+#[cfg_attr(feature = "gix", allow(unused))]
+mod cli;
 pub mod error;
+pub mod mirror;
+#[cfg(feature = "gix")]
+mod native;
+
+// Only the read-only operations are performed in-process when the gix
+// feature is enabled: resolving revisions, checking for shallow
+// repositories, reading files and listing commits. Everything touching the
+// network or the working tree is always done via the git CLI.
+#[cfg(not(feature = "gix"))]
+use cli as backend;
+#[cfg(feature = "gix")]
+use native as backend;
 
 use error::{Error, Result};
+use log::warn;
 use std::{
     ffi::OsStr,
     process::{Command, Output},
+    thread,
+    time::Duration,
 };
 
+/// The number of attempts made to clone or fetch from a network remote.
+const CLONE_ATTEMPTS: u32 = 3;
+/// The delay before the first retry, which is doubled on each attempt.
+const CLONE_BACKOFF: Duration = Duration::from_secs(2);
+/// Parts of error messages of git which indicate a transient network
+/// failure, matched case-insensitively.
+const TRANSIENT_ERRORS: [&str; 11] = [
+    "could not resolve host",
+    "connection timed out",
+    "connection refused",
+    "connection reset",
+    "operation timed out",
+    "early eof",
+    "unexpected disconnect",
+    "the remote end hung up unexpectedly",
+    "temporary failure in name resolution",
+    "rpc failed",
+    "http 50",
+];
+
+/// Options defining how the working tree of a repository is set up.
+#[derive(Default, Clone, Debug)]
+pub struct CheckoutOptions {
+    /// Limits the history to the given number of commits. If not set, the
+    /// full history is fetched.
+    pub depth: Option<u32>,
+    pub submodules: Submodules,
+    pub lfs: bool,
+    /// Only checks out the given paths if not empty.
+    pub sparse: Vec<String>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Submodules {
+    #[default]
+    Disabled,
+    Enabled,
+    Recursive,
+}
+
+/// The maximum number of commits listed for a single push.
+pub const MAX_PUSH_COMMITS: usize = 100;
+
+/// Metadata of a single commit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub sha: String,
+    pub short_sha: String,
+    pub author: Signature,
+    pub committer: Signature,
+    /// The commit time in seconds since the unix epoch.
+    pub timestamp: i64,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+}
+
+impl CommitInfo {
+    fn new(
+        sha: String,
+        author: Signature,
+        committer: Signature,
+        timestamp: i64,
+        message: &str,
+    ) -> Self {
+        let message = message.trim();
+        let (subject, body) = message.split_once("\n\n").unwrap_or((message, ""));
+        Self {
+            short_sha: sha.chars().take(7).collect(),
+            sha,
+            author,
+            committer,
+            timestamp,
+            subject: subject.lines().map(str::trim).collect::<Vec<_>>().join(" "),
+            body: body.trim().to_string(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Repository {
-    remote: String,
     dir: String,
     reference: String,
 }
 
 impl Repository {
-    pub fn clone<S: Into<String>>(remote: S, dir: S) -> Result<Self> {
+    /// Initializes a repository in `dir` and only fetches the given commit
+    /// (or the default branch if none is given) from the remote without
+    /// any history and without checking it out. Use [`Repository::prepare`]
+    /// to fetch the history and set up the working tree afterwards.
+    pub fn fetch<S: Into<String>>(remote: S, dir: S, commit: Option<&str>) -> Result<Self> {
         let remote: String = remote.into();
         let dir: String = dir.into();
-        let reference: String = "HEAD".into();
 
-        let _ = cmd(["clone", &remote, &dir]);
+        cmd(["init", "--quiet", &dir])?;
+        cmd(["-C", &dir, "remote", "add", "origin", &remote])?;
+        clone_cmd(&remote, &dir, || {
+            cmd([
+                "-C",
+                &dir,
+                "fetch",
+                "--quiet",
+                "--depth",
+                "1",
+                "origin",
+                commit.unwrap_or("HEAD"),
+            ])
+        })?;
 
-        Ok(Self {
-            remote,
-            dir,
-            reference,
-        })
+        let reference = match commit {
+            Some(commit) => commit.to_string(),
+            None => backend::rev_parse(&dir, "FETCH_HEAD")?,
+        };
+
+        Ok(Self { dir, reference })
+    }
+
+    /// Clones the given local mirror into `dir` sharing its objects without
+    /// checking it out. The origin of the clone is pointed to the given
+    /// remote afterwards.
+    pub fn clone_shared<S: Into<String>>(
+        remote: S,
+        mirror: S,
+        dir: S,
+        commit: Option<&str>,
+    ) -> Result<Self> {
+        let remote: String = remote.into();
+        let mirror: String = mirror.into();
+        let dir: String = dir.into();
+
+        cmd([
+            "clone",
+            "--quiet",
+            "--shared",
+            "--no-checkout",
+            &mirror,
+            &dir,
+        ])?;
+        let reference = backend::rev_parse(&dir, commit.unwrap_or("HEAD"))?;
+        cmd(["-C", &dir, "remote", "set-url", "origin", &remote])?;
+
+        Ok(Self { dir, reference })
+    }
+
+    /// Reads the file at the given path from the current reference without
+    /// requiring it to be checked out. Returns `None` if the file does not
+    /// exist.
+    pub fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        backend::read_file(&self.dir, &self.reference, path)
+    }
+
+    /// Returns the metadata of the commit of the current reference.
+    pub fn commit_info(&self) -> Result<CommitInfo> {
+        backend::log(&self.dir, &self.reference, None, 1)?
+            .pop()
+            .ok_or_else(|| Error::UnknownRevision(self.reference.clone()))
+    }
+
+    /// Returns the commits which have been added since the given commit,
+    /// newest first. If the given commit is unknown, for example because
+    /// the reference has been created or the history is shallow, only the
+    /// commit of the current reference is returned.
+    pub fn commits_since(&self, before: Option<&str>) -> Result<Vec<CommitInfo>> {
+        let before = before.filter(|b| !is_null_oid(b) && backend::rev_parse(&self.dir, b).is_ok());
+        match before {
+            Some(before) => {
+                backend::log(&self.dir, &self.reference, Some(before), MAX_PUSH_COMMITS)
+            }
+            None => self.commit_info().map(|info| vec![info]),
+        }
+    }
+
+    /// Fetches the history of the current reference as configured in the
+    /// given options and checks it out including submodules and LFS
+    /// objects if requested.
+    pub fn prepare(&mut self, options: &CheckoutOptions) -> Result<()> {
+        let reference = self.reference.clone();
+
+        if backend::is_shallow(&self.dir)? {
+            match options.depth {
+                Some(depth) => cmd([
+                    "-C",
+                    &self.dir,
+                    "fetch",
+                    "--quiet",
+                    "--depth",
+                    &depth.to_string(),
+                    "origin",
+                    &reference,
+                ])?,
+                None => cmd([
+                    "-C",
+                    &self.dir,
+                    "fetch",
+                    "--quiet",
+                    "--unshallow",
+                    "--tags",
+                    "origin",
+                    "+refs/heads/*:refs/remotes/origin/*",
+                ])?,
+            };
+        }
+
+        if !options.sparse.is_empty() {
+            let mut args = vec!["-C", &self.dir, "sparse-checkout", "set", "--no-cone"];
+            args.extend(options.sparse.iter().map(String::as_str));
+            cmd(args)?;
+        }
+
+        self.checkout(reference)?;
+
+        if options.submodules != Submodules::Disabled {
+            let depth = options.depth.map(|d| d.to_string());
+            let mut args = vec!["-C", &self.dir, "submodule", "update", "--init"];
+            if options.submodules == Submodules::Recursive {
+                args.push("--recursive");
+            }
+            if let Some(depth) = &depth {
+                args.extend(["--depth", depth.as_str()]);
+            }
+            cmd(args).map_err(|err| Error::Submodule(Box::new(err)))?;
+        }
+
+        if options.lfs {
+            cmd(["-C", &self.dir, "lfs", "pull"]).map_err(|err| Error::Lfs(Box::new(err)))?;
+        }
+
+        Ok(())
     }
 
     pub fn checkout<S: Into<String>>(&mut self, reference: S) -> Result<Output> {
@@ -37,39 +266,83 @@ impl Repository {
         Ok(out)
     }
 
-    pub fn get_ref_name(&self) -> Result<String> {
-        cmd(["-C", &self.dir, "describe", "--tags", "--exact-match"])
-            .and_then(|v| String::from_utf8(v.stdout).map_err(Error::OutputEncode))
-            .or_else(|_| {
-                cmd([
-                    "-C",
-                    &self.dir,
-                    "branch",
-                    "-a",
-                    "--contains",
-                    &self.reference,
-                ])
-                .and_then(|v| String::from_utf8(v.stdout).map_err(Error::OutputEncode))
-                .map(|v| {
-                    v.split('\n')
-                        .nth(1)
-                        .unwrap_or(&self.reference)
-                        .trim()
-                        .to_string()
-                })
-            })
+    pub fn get_ref(&self) -> &str {
+        &self.reference
     }
+}
 
-    pub fn get_remote(&self) -> &str {
-        &self.remote
-    }
+/// Returns true if the given object ID is the all-zero ID which git passes
+/// to hooks for references that have been created or deleted.
+pub fn is_null_oid(oid: &str) -> bool {
+    !oid.is_empty() && oid.chars().all(|c| c == '0')
+}
 
-    pub fn get_dir(&self) -> &str {
-        &self.dir
+/// Runs the given git operations on the blocking thread pool so that the
+/// async runtime is not stalled while waiting for git.
+pub async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// Runs the given clone or fetch command and maps its failure to
+/// [`Error::Clone`]. Commands against network remotes which failed due to
+/// a transient error are retried with an exponential backoff.
+pub(crate) fn clone_cmd<F>(remote: &str, dir: &str, mut f: F) -> Result<Output>
+where
+    F: FnMut() -> Result<Output>,
+{
+    let attempts = if is_network_remote(remote) {
+        CLONE_ATTEMPTS
+    } else {
+        1
+    };
+    let mut backoff = CLONE_BACKOFF;
+
+    for attempt in 1.. {
+        match f() {
+            Err(Error::CommandStatus { message, .. })
+                if attempt >= attempts || !is_transient(&message) =>
+            {
+                return Err(Error::Clone {
+                    remote: remote.to_string(),
+                    dir: dir.to_string(),
+                    message: message.trim().to_string(),
+                })
+            }
+            Err(Error::CommandStatus { message, .. }) => {
+                warn!(
+                    "Fetching {remote} failed (attempt {attempt}/{attempts}), retrying in {backoff:?}: {}",
+                    message.trim()
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            res => return res,
+        }
     }
 
-    pub fn get_ref(&self) -> &str {
-        &self.reference
+    unreachable!()
+}
+
+/// Returns whether the given error message of git indicates a failure which
+/// might succeed when retried.
+fn is_transient(message: &str) -> bool {
+    let message = message.to_lowercase();
+    TRANSIENT_ERRORS.iter().any(|e| message.contains(e))
+}
+
+/// Returns whether the given remote is accessed over the network, either
+/// via an URL like `https://host/repo` or the scp-like `user@host:repo`.
+fn is_network_remote(remote: &str) -> bool {
+    if let Some((scheme, _)) = remote.split_once("://") {
+        return scheme != "file";
+    }
+    match remote.split_once(':') {
+        Some((host, _)) => !host.is_empty() && !host.contains('/'),
+        None => false,
     }
 }
 
@@ -88,3 +361,126 @@ where
 
     Ok(out)
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn network_remotes() {
+        assert!(is_network_remote("https://github.com/zekroTJA/minicd"));
+        assert!(is_network_remote("ssh://git@github.com/zekroTJA/minicd"));
+        assert!(is_network_remote("git@github.com:zekroTJA/minicd.git"));
+        assert!(!is_network_remote("/home/git/repos/minicd.git"));
+        assert!(!is_network_remote("file:///home/git/repos/minicd.git"));
+        assert!(!is_network_remote("./repos/with:colon"));
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(
+            "fatal: unable to access 'https://example.com/repo.git/': Could not resolve host: example.com"
+        ));
+        assert!(is_transient(
+            "ssh: connect to host example.com port 22: Connection timed out"
+        ));
+        assert!(is_transient(
+            "fetch-pack: unexpected disconnect while reading sideband packet\nfatal: early EOF"
+        ));
+        assert!(is_transient(
+            "error: RPC failed; HTTP 502 curl 22 The requested URL returned error: 502"
+        ));
+        assert!(!is_transient(
+            "fatal: repository 'https://example.com/missing.git/' not found"
+        ));
+        assert!(!is_transient(
+            "fatal: Authentication failed for 'https://example.com/repo.git/'"
+        ));
+        assert!(!is_transient(
+            "fatal: couldn't find remote ref refs/heads/missing"
+        ));
+    }
+
+    pub fn commit(dir: &str, file: &str, message: &str) {
+        std::fs::write(Path::new(dir).join(file), message).unwrap();
+        cmd(["-C", dir, "add", file]).unwrap();
+        cmd([
+            "-C",
+            dir,
+            "-c",
+            "user.name=minicd",
+            "-c",
+            "user.email=minicd@localhost",
+            "commit",
+            "--quiet",
+            "-m",
+            message,
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn fetch_and_read_file() {
+        let remote = temp_dir::TempDir::new().unwrap();
+        let remote_dir = remote.path().to_str().unwrap();
+        cmd(["init", "--quiet", remote_dir]).unwrap();
+        commit(remote_dir, ".minicd", "name: test");
+
+        let dir = temp_dir::TempDir::new().unwrap();
+        let repo = Repository::fetch(remote_dir, dir.path().to_str().unwrap(), None).unwrap();
+
+        assert_eq!(cli::rev_parse(remote_dir, "HEAD").unwrap(), repo.get_ref());
+        assert_eq!(
+            Some(b"name: test".to_vec()),
+            repo.read_file(".minicd").unwrap()
+        );
+        assert_eq!(None, repo.read_file("missing").unwrap());
+    }
+
+    #[test]
+    fn commits_since() {
+        let remote = temp_dir::TempDir::new().unwrap();
+        let remote_dir = remote.path().to_str().unwrap();
+        cmd(["init", "--quiet", remote_dir]).unwrap();
+        commit(remote_dir, "a", "first");
+        let before = cli::rev_parse(remote_dir, "HEAD").unwrap();
+        commit(remote_dir, "b", "second\n\nwith body");
+        commit(remote_dir, "c", "third");
+
+        let dir = temp_dir::TempDir::new().unwrap();
+        let mut repo = Repository::fetch(remote_dir, dir.path().to_str().unwrap(), None).unwrap();
+
+        // Only the fetched commit is known in a shallow repository.
+        let commits = repo.commits_since(Some(&before)).unwrap();
+        assert_eq!(1, commits.len());
+
+        repo.prepare(&CheckoutOptions::default()).unwrap();
+        let commits = repo.commits_since(Some(&before)).unwrap();
+        assert_eq!(
+            vec!["third", "second"],
+            commits
+                .iter()
+                .map(|c| c.subject.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("with body", commits[1].body);
+        assert_eq!("minicd@localhost", commits[0].author.email);
+        assert_eq!(commits[0].sha[..7], commits[0].short_sha);
+        assert_eq!(commits[0], repo.commit_info().unwrap());
+    }
+
+    #[test]
+    fn fetch_fails_with_context() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let err = Repository::fetch("/does/not/exist", dir.path().to_str().unwrap(), None)
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            err,
+            Error::Clone { ref remote, ref message, .. }
+                if remote == "/does/not/exist" && !message.is_empty()
+        ));
+    }
+}
@@ -0,0 +1,62 @@
+use super::{error::Result, CommitInfo, Signature};
+
+pub fn rev_parse(dir: &str, spec: &str) -> Result<String> {
+    let repo = open(dir)?;
+    let commit = repo.rev_parse_single(spec)?.object()?.peel_to_commit()?;
+    Ok(commit.id.to_string())
+}
+
+pub fn is_shallow(dir: &str) -> Result<bool> {
+    Ok(open(dir)?.is_shallow())
+}
+
+pub fn read_file(dir: &str, reference: &str, path: &str) -> Result<Option<Vec<u8>>> {
+    let repo = open(dir)?;
+    let tree = repo
+        .rev_parse_single(reference)?
+        .object()?
+        .peel_to_commit()?
+        .tree()?;
+
+    match tree.lookup_entry_by_path(path)? {
+        Some(entry) => Ok(Some(entry.object()?.detach().data)),
+        None => Ok(None),
+    }
+}
+
+pub fn log(dir: &str, reference: &str, since: Option<&str>, max: usize) -> Result<Vec<CommitInfo>> {
+    let repo = open(dir)?;
+    let tip = repo.rev_parse_single(reference)?;
+    let hidden = match since {
+        Some(since) => vec![repo.rev_parse_single(since)?.detach()],
+        None => vec![],
+    };
+
+    let walk = repo.rev_walk([tip]).with_hidden(hidden).all()?;
+
+    let mut commits = vec![];
+    for info in walk.take(max) {
+        let commit = info?.object()?;
+        let author = commit.author()?.trim();
+        let committer = commit.committer()?.trim();
+        commits.push(CommitInfo::new(
+            commit.id.to_string(),
+            Signature {
+                name: author.name.to_string(),
+                email: author.email.to_string(),
+            },
+            Signature {
+                name: committer.name.to_string(),
+                email: committer.email.to_string(),
+            },
+            committer.seconds(),
+            &commit.message_raw_sloppy().to_string(),
+        ));
+    }
+
+    Ok(commits)
+}
+
+fn open(dir: &str) -> Result<gix::Repository> {
+    Ok(gix::open(dir)?)
+}
@@ -1,4 +1,8 @@
 mod api;
+mod archive;
+mod artifacts;
+mod cache;
+mod cli;
 mod config;
 mod definition;
 mod git;
@@ -6,43 +10,133 @@ mod mailing;
 mod repos;
 mod runner;
 mod secrets;
+mod state;
 
-use crate::{mailing::MailSender, runner::Runner, secrets::SecretManager};
+use crate::{
+    artifacts::ArtifactStore,
+    cache::CacheStore,
+    git::mirror::MirrorStore,
+    mailing::MailSender,
+    runner::{Runner, Settings},
+    secrets::SecretManager,
+    state::StateStore,
+};
 use config::Config;
 use env_logger::Env;
-use log::{debug, error};
-use std::{error::Error, time::Duration};
+use log::{debug, error, info, warn};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info"))
         .try_init()
         .expect("failed initializing logger");
 
     let cfg = Config::parse()?;
 
-    debug!("config: {:#?}", &cfg);
+    debug!("config: {:#?}", cfg.redacted());
 
-    let secrets = cfg
-        .secrets_file
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&cfg, &args);
+    }
+
+    // Jobs inherit the environment of minicd, so private variables and the
+    // ones read by env secret providers are removed once before the runtime
+    // starts any threads, as modifying the environment is not thread-safe.
+    secrets::environment::scrub(&cfg.env_secret_prefixes());
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(cfg))
+}
+
+async fn serve(cfg: Config) -> Result<(), Box<dyn Error>> {
+    let settings = load_settings(&cfg).await?;
+
+    let state = StateStore::open(cfg.data_dir.as_ref())?;
+
+    let artifacts_dir = cfg
+        .artifacts
         .as_ref()
-        .map(SecretManager::new)
-        .unwrap_or_else(|| Ok(SecretManager::empty()))?;
+        .and_then(|ac| ac.dir.clone())
+        .or_else(|| cfg.data_dir.as_ref().map(|d| d.join("artifacts")));
+    let artifacts = artifacts_dir
+        .map(|dir| {
+            let retention = cfg
+                .artifacts
+                .as_ref()
+                .and_then(|ac| ac.retention_secs)
+                .map(Duration::from_secs);
+            ArtifactStore::new(dir, retention)
+        })
+        .transpose()?;
 
-    let mailer = cfg
-        .email
+    let cache_dir = cfg
+        .cache
         .as_ref()
-        .map(|mc| {
-            MailSender::new(
-                &mc.smtp_server,
-                &mc.username,
-                &mc.password,
-                &mc.from_address,
-            )
+        .and_then(|cc| cc.dir.clone())
+        .or_else(|| cfg.data_dir.as_ref().map(|d| d.join("cache")));
+    let cache = cache_dir
+        .map(|dir| {
+            let max_size = cfg
+                .cache
+                .as_ref()
+                .and_then(|cc| cc.max_size_mb)
+                .map(|mb| mb * 1024 * 1024);
+            CacheStore::new(dir, max_size)
         })
         .transpose()?;
 
-    let runner = Runner::new(secrets, mailer);
+    let mirrors = match (cfg.mirror_repos, &cfg.data_dir) {
+        (Some(true), Some(data_dir)) => Some(MirrorStore::new(data_dir.join("mirrors"))),
+        (Some(true), None) => {
+            warn!("Repository mirrors are disabled because no data_dir has been configured");
+            None
+        }
+        _ => None,
+    };
+
+    let runner = Runner::new(settings, state, artifacts, cache, mirrors);
+
+    #[cfg(unix)]
+    {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let runner = runner.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config ...");
+                reload(&runner).await;
+            }
+        });
+    }
+
+    if cfg.watch_config == Some(true) {
+        let mut files = cfg.watched_files();
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        let runner = runner.clone();
+        tokio::spawn(async move {
+            let mut modified = modification_times(&files);
+            loop {
+                interval.tick().await;
+                let current = modification_times(&files);
+                if current != modified {
+                    info!("Config files changed, reloading config ...");
+                    if let Some(cfg) = reload(&runner).await {
+                        files = cfg.watched_files();
+                    }
+                    modified = modification_times(&files);
+                }
+            }
+        });
+    }
 
     if let Some(repo_dir) = cfg.repo_dir.clone() {
         let mut interval =
@@ -59,7 +153,128 @@ async fn main() -> Result<(), Box<dyn Error>> {
         });
     }
 
+    if let Some(ttl) = cfg.environment_ttl_secs {
+        let ttl = Duration::from_secs(ttl);
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        let runner = runner.clone();
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                debug!("Checking for idle environments ...");
+                runner.stop_idle_environments(ttl).await;
+            }
+        });
+    }
+
     api::run(&cfg, runner).await?;
 
     Ok(())
 }
+
+/// Loads the settings of the runner which can be reloaded at runtime.
+async fn load_settings(cfg: &Config) -> Result<Settings, Box<dyn Error>> {
+    let secrets = SecretManager::load(&secrets::providers::from_config(cfg)?).await?;
+
+    let mailer = cfg
+        .email
+        .as_ref()
+        .map(|mc| {
+            MailSender::new(
+                &mc.smtp_server,
+                &mc.username,
+                &mc.password,
+                &mc.from_address,
+            )
+        })
+        .transpose()?;
+
+    Ok(Settings {
+        secrets,
+        secret_policies: cfg.secret_policies.clone(),
+        approval_policies: cfg.approval_policies.clone(),
+        mailer,
+        public_url: cfg.public_url.clone(),
+        repo_dir: cfg.repo_dir.clone(),
+        api_tokens: cfg.api_tokens.clone(),
+    })
+}
+
+/// Reloads the config and swaps the settings of the runner. On failure the
+/// previous settings are kept.
+async fn reload(runner: &Runner) -> Option<Config> {
+    let cfg = match Config::parse() {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("Reloading config failed, keeping previous config: {err}");
+            return None;
+        }
+    };
+    match load_settings(&cfg).await {
+        Ok(settings) => {
+            runner.reload(settings);
+            info!("Config reloaded");
+            Some(cfg)
+        }
+        Err(err) => {
+            error!("Reloading config failed, keeping previous config: {err}");
+            None
+        }
+    }
+}
+
+/// Returns the modification times of the given files. Directories are
+/// walked recursively, as editing a file in place does not change the
+/// modification time of its directory.
+fn modification_times(files: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut times = vec![];
+    for file in files {
+        collect_modification_times(file, &mut times);
+    }
+    times
+}
+
+fn collect_modification_times(path: &Path, times: &mut Vec<(PathBuf, Option<SystemTime>)>) {
+    times.push((
+        path.to_path_buf(),
+        path.metadata().and_then(|m| m.modified()).ok(),
+    ));
+
+    // Hidden entries are skipped like by the directory secret provider.
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries
+        .flatten()
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        collect_modification_times(&entry, times);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modification_times_of_directories() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("docker")).unwrap();
+        let file = dir.path().join("docker").join("password");
+        std::fs::write(&file, "a").unwrap();
+        let files = vec![dir.path().to_path_buf()];
+
+        let before = modification_times(&files);
+        assert_eq!(3, before.len());
+
+        // Editing the file in place keeps the times of the directories.
+        let modified = SystemTime::now() + Duration::from_secs(60);
+        let f = std::fs::OpenOptions::new().write(true).open(&file).unwrap();
+        f.set_modified(modified).unwrap();
+        let after = modification_times(&files);
+        assert_ne!(before, after);
+        assert_eq!(before[..2], after[..2]);
+    }
+}
@@ -1,7 +1,13 @@
-use std::{error::Error, fs::File, io::Write, path::Path};
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
 use walkdir::WalkDir;
 
-const HOOK_FILE_VERSION: u8 = 1;
+const HOOK_FILE_VERSION: u8 = 3;
+const HOOK_FILE_VERSION_MARKER: &str = "minicd::hookfile_version";
 
 pub fn index(dir: impl AsRef<Path>, port: u16) -> Result<(), Box<dyn Error>> {
     for entry in WalkDir::new(dir) {
@@ -19,9 +25,15 @@ pub fn index(dir: impl AsRef<Path>, port: u16) -> Result<(), Box<dyn Error>> {
             let pr_hook_dir = hooks_dir.join("post-receive");
 
             if pr_hook_dir.exists() {
-                // TODO: Check for hook file version and replace it
-                //       if there is a new version.
-                return Ok(());
+                // Hook files which have not been generated by minicd are left
+                // untouched. Generated ones are replaced on version changes.
+                let content = fs::read_to_string(&pr_hook_dir)?;
+                match hook_file_version(&content) {
+                    Some(version) if version < HOOK_FILE_VERSION => {
+                        log::info!("Updating outdated hook file at {}", pr_hook_dir.display());
+                    }
+                    _ => continue,
+                }
             }
 
             let mut pr_hook_file = File::create(pr_hook_dir)?;
@@ -38,11 +50,11 @@ pub fn index(dir: impl AsRef<Path>, port: u16) -> Result<(), Box<dyn Error>> {
                 "#!/bin/bash\n\
                 \n\
                 # This file has been auto-generated by minicd.\n\
-                # minicd::hookfile_version {HOOK_FILE_VERSION}\n\
+                # {HOOK_FILE_VERSION_MARKER} {HOOK_FILE_VERSION}\n\
                 \n\
                 while read old_commit new_commit ref_name; do\n\
                     curl -X POST http://127.0.0.1:{port}/api/postreceive \\\n\
-                    \t-d \"{} $new_commit $ref_name\"\n\
+                    \t-d \"{} $new_commit $ref_name ${{USER:-$(whoami)}} $old_commit\"\n\
                 done
                 ",
                 path.to_string_lossy()
@@ -52,3 +64,10 @@ pub fn index(dir: impl AsRef<Path>, port: u16) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+fn hook_file_version(content: &str) -> Option<u8> {
+    content
+        .lines()
+        .find_map(|line| line.split_once(HOOK_FILE_VERSION_MARKER))
+        .and_then(|(_, version)| version.trim().parse().ok())
+}
@@ -0,0 +1,280 @@
+use super::error::{Error, Result};
+use crate::{config::ApprovalPolicy, definition::Approval};
+use glob::Pattern;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tokio::sync::oneshot;
+
+/// A job which is waiting for a manual approval.
+#[derive(Serialize, Clone, Debug)]
+pub struct PendingApproval {
+    pub id: u64,
+    pub name: String,
+    pub remote: String,
+    pub reference: String,
+    pub commit: String,
+    pub job: String,
+    pub requested_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+pub enum Decision {
+    Approved(String),
+    Rejected(String),
+    Expired,
+}
+
+struct Entry {
+    approval: PendingApproval,
+    approvers: Vec<(String, String)>,
+    sender: oneshot::Sender<Decision>,
+}
+
+/// Registry of jobs waiting for approval. Approvals are only held in
+/// memory, so pending approvals are lost when minicd is restarted.
+#[derive(Default)]
+pub struct Approvals {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, Entry>>,
+}
+
+impl Approvals {
+    /// Registers a new pending approval which can be decided by any of the
+    /// given approvers, passed as pairs of name and token. The ID of the
+    /// given approval is overwritten with the assigned ID. The approval is
+    /// removed when the returned guard is dropped.
+    pub fn request(
+        &self,
+        mut approval: PendingApproval,
+        approvers: Vec<(String, String)>,
+    ) -> (Request<'_>, oneshot::Receiver<Decision>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        approval.id = id;
+
+        let (sender, receiver) = oneshot::channel();
+        self.lock().insert(
+            id,
+            Entry {
+                approval,
+                approvers,
+                sender,
+            },
+        );
+
+        (
+            Request {
+                approvals: self,
+                id,
+            },
+            receiver,
+        )
+    }
+
+    pub fn pending(&self) -> Vec<PendingApproval> {
+        let mut pending: Vec<_> = self.lock().values().map(|e| e.approval.clone()).collect();
+        pending.sort_by_key(|a| a.id);
+        pending
+    }
+
+    /// Approves or rejects the pending approval with the given ID if the
+    /// token belongs to one of its approvers. Returns the approval.
+    pub fn decide(&self, id: u64, token: &str, approve: bool) -> Result<PendingApproval> {
+        let mut pending = self.lock();
+
+        let entry = pending.get(&id).ok_or(Error::ApprovalNotFound(id))?;
+        let Some((name, _)) = entry.approvers.iter().find(|(_, t)| token_eq(t, token)) else {
+            return Err(Error::ApprovalUnauthorized(id));
+        };
+
+        let decision = if approve {
+            Decision::Approved(name.clone())
+        } else {
+            Decision::Rejected(name.clone())
+        };
+
+        let entry = pending.remove(&id).ok_or(Error::ApprovalNotFound(id))?;
+        // The receiving job might already be gone, which is fine.
+        let _ = entry.sender.send(decision);
+
+        Ok(entry.approval)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Entry>> {
+        self.pending.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Returns the approval required by the policies matching the repository
+/// and environment, if any. The approvers of all matching policies are
+/// merged and the shortest timeout is used.
+pub fn required_approval(
+    policies: &[ApprovalPolicy],
+    remote: &str,
+    environment: &str,
+) -> Option<Approval> {
+    let matches = |patterns: &[String], value: &str| {
+        patterns
+            .iter()
+            .any(|p| Pattern::new(p).is_ok_and(|p| p.matches(value)))
+    };
+
+    let mut approval: Option<Approval> = None;
+    for policy in policies
+        .iter()
+        .filter(|p| matches(&p.repos, remote) && matches(&p.environments, environment))
+    {
+        let approval = approval.get_or_insert_with(|| Approval {
+            approvers: vec![],
+            timeout_secs: policy.timeout_secs,
+        });
+        for approver in &policy.approvers {
+            if !approval.approvers.contains(approver) {
+                approval.approvers.push(approver.clone());
+            }
+        }
+        approval.timeout_secs = match (approval.timeout_secs, policy.timeout_secs) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+    approval
+}
+
+/// Compares the tokens in constant time, so that the time taken does not
+/// reveal how much of a guessed token is correct.
+pub fn token_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Guard of a requested approval which removes it once the waiting job
+/// stops waiting for it.
+pub struct Request<'a> {
+    approvals: &'a Approvals,
+    pub id: u64,
+}
+
+impl Drop for Request<'_> {
+    fn drop(&mut self) {
+        self.approvals.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn approval() -> PendingApproval {
+        PendingApproval {
+            id: 0,
+            name: "app".into(),
+            remote: "/repos/app.git".into(),
+            reference: "refs/heads/main".into(),
+            commit: "abc".into(),
+            job: "deploy".into(),
+            requested_at: 0,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn decide() {
+        let approvals = Approvals::default();
+        let approvers = vec![("alice".into(), "t1".into()), ("bob".into(), "t2".into())];
+        let (request, mut receiver) = approvals.request(approval(), approvers);
+        assert_eq!(1, approvals.pending()[0].id);
+
+        assert!(matches!(
+            approvals.decide(request.id, "t3", true),
+            Err(Error::ApprovalUnauthorized(1))
+        ));
+        assert!(matches!(
+            approvals.decide(request.id, "", true),
+            Err(Error::ApprovalUnauthorized(1))
+        ));
+        assert!(matches!(
+            approvals.decide(2, "t1", true),
+            Err(Error::ApprovalNotFound(2))
+        ));
+        assert!(receiver.try_recv().is_err());
+
+        let decided = approvals.decide(request.id, "t2", false).unwrap();
+        assert_eq!("deploy", decided.job);
+        assert!(matches!(receiver.try_recv(), Ok(Decision::Rejected(name)) if name == "bob"));
+        assert!(approvals.pending().is_empty());
+        assert!(matches!(
+            approvals.decide(request.id, "t2", true),
+            Err(Error::ApprovalNotFound(1))
+        ));
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(token_eq("secret", "secret"));
+        assert!(!token_eq("secret", "secreT"));
+        assert!(!token_eq("secret", "secret "));
+        assert!(!token_eq("", "secret"));
+        assert!(token_eq("", ""));
+    }
+
+    #[test]
+    fn required_approvals() {
+        let policy =
+            |repos: &[&str], environments: &[&str], approvers: &[&str], timeout| ApprovalPolicy {
+                repos: repos.iter().map(|v| v.to_string()).collect(),
+                environments: environments.iter().map(|v| v.to_string()).collect(),
+                approvers: approvers.iter().map(|v| v.to_string()).collect(),
+                timeout_secs: timeout,
+            };
+        let policies = [
+            policy(&["/repos/*"], &["production"], &["alice"], None),
+            policy(
+                &["/repos/app.git"],
+                &["prod*"],
+                &["bob", "alice"],
+                Some(600),
+            ),
+        ];
+
+        assert_eq!(
+            Some(Approval {
+                approvers: vec!["alice".into(), "bob".into()],
+                timeout_secs: Some(600),
+            }),
+            required_approval(&policies, "/repos/app.git", "production")
+        );
+        assert_eq!(
+            Some(Approval {
+                approvers: vec!["alice".into()],
+                timeout_secs: None,
+            }),
+            required_approval(&policies, "/repos/other.git", "production")
+        );
+        assert_eq!(
+            None,
+            required_approval(&policies, "/repos/other.git", "preview")
+        );
+        assert_eq!(None, required_approval(&[], "/repos/app.git", "production"));
+    }
+
+    #[test]
+    fn remove_on_drop() {
+        let approvals = Approvals::default();
+        let (request, _receiver) =
+            approvals.request(approval(), vec![("alice".into(), "t1".into())]);
+        assert_eq!(1, approvals.pending().len());
+
+        drop(request);
+        assert!(approvals.pending().is_empty());
+        assert!(matches!(
+            approvals.decide(1, "t1", true),
+            Err(Error::ApprovalNotFound(1))
+        ));
+    }
+}
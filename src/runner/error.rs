@@ -1,4 +1,4 @@
-use crate::{definition::RefParseError, git, mailing};
+use crate::{artifacts, definition::RefParseError, git, mailing, secrets, state};
 use http::method::InvalidMethod;
 use run_script::ScriptError;
 
@@ -21,9 +21,18 @@ pub enum Error {
     #[error("failed deserializing definition file: {0}")]
     FailedDeserializingDefinitionFile(#[from] serde_yaml::Error),
 
+    #[error("invalid placeholder in definition: {0}")]
+    Placeholder(secrets::error::Error),
+
+    #[error("failed writing secret file: {0}")]
+    SecretFile(secrets::error::Error),
+
     #[error("script execution failed: {0}")]
     Script(#[from] ScriptError),
 
+    #[error("failed accessing job output file: {0}")]
+    OutputFile(std::io::Error),
+
     #[error("script failed with non-zero exit code {exit_code}: {std_err}")]
     ScriptNonZeroExitCode { exit_code: i32, std_err: String },
 
@@ -36,11 +45,44 @@ pub enum Error {
     #[error("notification webhook request failed: {0}")]
     WebhookFailed(#[from] reqwest::Error),
 
+    #[error("invalid notification url {0}")]
+    InvalidNotificationUrl(String),
+
     #[error("notification mail send failed: {0}")]
     MailFailed(#[from] mailing::error::Error),
 
     #[error(transparent)]
     InvalidReferenceName(#[from] RefParseError),
+
+    #[error("artifact collection failed: {0}")]
+    Artifacts(#[from] artifacts::error::Error),
+
+    #[error("state operation failed: {0}")]
+    State(#[from] state::error::Error),
+
+    #[error("environment {0} not found")]
+    EnvironmentNotFound(String),
+
+    #[error("environment name {0} is ambiguous, specify the remote")]
+    AmbiguousEnvironment(String),
+
+    #[error("environment {0} has no previous deployment to roll back to")]
+    NoRollbackTarget(String),
+
+    #[error("job {0} not found in definition")]
+    JobNotFound(String),
+
+    #[error("rollback of environment {0} failed")]
+    RollbackFailed(String),
+
+    #[error("not authorized to roll back environment {0}")]
+    RollbackUnauthorized(String),
+
+    #[error("approval {0} not found")]
+    ApprovalNotFound(u64),
+
+    #[error("not authorized to decide on approval {0}")]
+    ApprovalUnauthorized(u64),
 }
 
 impl From<(i32, String)> for Error {
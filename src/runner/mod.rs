@@ -1,159 +1,1075 @@
+pub mod approval;
 pub mod error;
+mod notifications;
+mod outputs;
 
 use crate::{
-    definition::{Definition, Job, JobState, Notify, NotifyTarget, Ref, ValueOrList},
-    git::Repository,
+    artifacts::ArtifactStore,
+    cache::{self, CacheStore},
+    config::{ApprovalPolicy, SecretPolicy},
+    definition::{
+        Approval, Cache, Definition, Job, JobState, Notify, NotifyTarget, Ref, SecretType,
+        ValueOrList,
+    },
+    git::{self, mirror::MirrorStore, Repository},
     mailing::MailSender,
-    secrets::SecretManager,
+    secrets::{files::SecretFiles, SecretManager},
+    state::{self, StateStore},
 };
+use approval::{Approvals, Decision, PendingApproval};
 use error::{Error, Result};
+use futures::{
+    future::{self, BoxFuture, Shared},
+    FutureExt,
+};
 use log::debug;
+use notifications::Notification;
 use reqwest::header::HeaderMap;
 use run_script::ScriptOptions;
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 use temp_dir::TempDir;
-use tokio::{fs::File, io::AsyncReadExt};
+use tokio::fs::File;
 
 struct RunnerData {
-    secrets: SecretManager,
-    mailer: Option<MailSender>,
+    settings: RwLock<Arc<Settings>>,
+    state: StateStore,
+    artifacts: Option<ArtifactStore>,
+    cache: Option<CacheStore>,
+    mirrors: Option<MirrorStore>,
+    approvals: Approvals,
 }
 
 pub struct Runner(Arc<RunnerData>);
 
+/// Settings which can be reloaded while minicd is running. Each run keeps
+/// the settings it has been started with.
+pub struct Settings {
+    pub secrets: SecretManager,
+    pub secret_policies: Vec<SecretPolicy>,
+    pub approval_policies: Vec<ApprovalPolicy>,
+    pub mailer: Option<MailSender>,
+    /// The URL under which the API is reachable, used to link runs in
+    /// notifications.
+    pub public_url: Option<String>,
+    /// The directory of the repositories. Secrets of repositories are looked
+    /// up by their path relative to it.
+    pub repo_dir: Option<PathBuf>,
+    /// Tokens of the users allowed to roll back environments, by user name.
+    pub api_tokens: HashMap<String, String>,
+}
+
 impl Clone for Runner {
     fn clone(&self) -> Self {
         Self(self.0.clone())
     }
 }
 
+/// Data shared between all jobs of a single run. The checkout directory
+/// is removed when the last job holding the context has finished.
+struct RunContext {
+    id: u64,
+    dir: TempDir,
+    name: String,
+    remote: String,
+    commit: String,
+    /// The commits of the push, newest first. The first entry is always
+    /// the checked out commit.
+    commits: Vec<git::CommitInfo>,
+    ref_typ: Ref,
+    triggered_by: String,
+    vars: HashMap<String, String>,
+    /// Environment variables passed to all jobs of the run.
+    env: HashMap<String, String>,
+    settings: Arc<Settings>,
+    /// The secrets the repository and reference may access according to
+    /// the configured policies.
+    secrets: SecretManager,
+    /// The `env` map of the definition. Its values are interpolated for
+    /// each job.
+    definition_env: HashMap<String, String>,
+    /// Whether unresolved placeholders fail the job instead of being left
+    /// untouched.
+    strict_placeholders: bool,
+}
+
+impl RunContext {
+    /// Replaces the placeholders in the given content with the variables
+    /// of the run and the given secrets, which are usually the ones
+    /// declared by the job (see [`RunContext::job_secrets`]).
+    fn replace(
+        &self,
+        content: &str,
+        vars: &HashMap<String, String>,
+        secrets: &SecretManager,
+    ) -> Result<String> {
+        if !self.strict_placeholders {
+            return Ok(secrets.replace(content, vars));
+        }
+        secrets
+            .replace_strict(content, vars)
+            .map_err(Error::Placeholder)
+    }
+
+    /// Replaces the placeholders in the values of the given environment.
+    fn replace_env(
+        &self,
+        env: HashMap<String, String>,
+        vars: &HashMap<String, String>,
+        secrets: &SecretManager,
+    ) -> Result<HashMap<String, String>> {
+        env.into_iter()
+            .map(|(k, v)| Ok((k, self.replace(&v, vars, secrets)?)))
+            .collect()
+    }
+
+    /// Returns the secrets of the run which the given job has declared in
+    /// its `secrets`. Placeholders of the job are only resolved against
+    /// these, so that a job can not read other secrets by referring to them
+    /// in its environment, notifications or cache keys.
+    fn job_secrets(&self, job: &Job) -> SecretManager {
+        let keys: Vec<_> = job.secrets.iter().flatten().map(|s| s.key()).collect();
+        self.secrets.filter(&keys)
+    }
+
+    fn notification<'a>(
+        &'a self,
+        state: JobState,
+        job_id: &'a str,
+        duration: Option<Duration>,
+        context: Option<&'a str>,
+    ) -> Notification<'a> {
+        Notification {
+            state,
+            project: &self.name,
+            job: job_id,
+            reference: &self.ref_typ,
+            commit: self.commits.first(),
+            duration,
+            context,
+            url: self
+                .settings
+                .public_url
+                .as_ref()
+                .map(|url| format!("{}/api/runs/{}", url.trim_end_matches('/'), self.id)),
+        }
+    }
+}
+
+/// The result of a finished job together with the outputs and the
+/// artifact archive it produced.
+#[derive(Clone)]
+struct JobResult {
+    state: JobState,
+    outputs: Arc<HashMap<String, String>>,
+    artifact: Option<PathBuf>,
+}
+
+type JobOutcome = Shared<BoxFuture<'static, JobResult>>;
+
+/// Describes how a job affects the environments tracked in the state.
+enum EnvironmentAction {
+    Deploy(state::Environment),
+    Stop(state::Environment),
+}
+
 impl Runner {
-    pub fn new(secrets: SecretManager, mailer: Option<MailSender>) -> Self {
-        Self(Arc::new(RunnerData { secrets, mailer }))
+    pub fn new(
+        settings: Settings,
+        state: StateStore,
+        artifacts: Option<ArtifactStore>,
+        cache: Option<CacheStore>,
+        mirrors: Option<MirrorStore>,
+    ) -> Self {
+        Self(Arc::new(RunnerData {
+            settings: RwLock::new(Arc::new(settings)),
+            state,
+            artifacts,
+            cache,
+            mirrors,
+            approvals: Approvals::default(),
+        }))
+    }
+
+    /// Replaces the settings used by new runs. Runs which are already in
+    /// progress keep using the previous settings.
+    pub fn reload(&self, settings: Settings) {
+        *self
+            .0
+            .settings
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = Arc::new(settings);
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.0
+            .settings
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    pub fn runs(&self) -> Vec<state::Run> {
+        self.0.state.runs()
+    }
+
+    pub fn get_run(&self, id: u64) -> Option<state::Run> {
+        self.0.state.run(id)
+    }
+
+    pub fn artifact_path(&self, run_id: u64, name: &str) -> Option<PathBuf> {
+        self.0.artifacts.as_ref()?.path(run_id, name)
+    }
+
+    pub fn environments(&self) -> Vec<state::Environment> {
+        self.0.state.environments()
+    }
+
+    pub fn deployments(&self, remote: Option<&str>, environment: &str) -> Vec<state::Deployment> {
+        self.0.state.deployments(remote, environment)
+    }
+
+    pub fn pending_approvals(&self) -> Vec<PendingApproval> {
+        self.0.approvals.pending()
     }
 
-    pub async fn run(&self, remote: &str, reference: &str, reference_name: &str) -> Result<()> {
-        let tmp_dir = TempDir::new().map_err(Error::TempDirCreationFailed)?;
-        let tmp_dir_path = tmp_dir.path();
+    pub fn decide_approval(&self, id: u64, token: &str, approve: bool) -> Result<PendingApproval> {
+        self.0.approvals.decide(id, token, approve)
+    }
 
+    pub async fn run(
+        &self,
+        remote: &str,
+        reference: &str,
+        reference_name: &str,
+        triggered_by: &str,
+        before: Option<&str>,
+    ) -> Result<()> {
         let ref_typ: Ref = reference_name.parse()?;
 
-        let mut repo = Repository::clone(remote, tmp_dir_path.to_str().unwrap_or_default())?;
-        repo.checkout(reference)?;
+        // When a reference has been deleted, there is no tree to check out, so
+        // the definition is read from the default branch of the repository.
+        let deleted = git::is_null_oid(reference);
 
-        let def_path = tmp_dir_path.join(".minicd");
-        if !def_path.exists() {
-            return Err(Error::NoDefinitionFile);
-        }
+        let (ctx, def) = self
+            .checkout(
+                remote,
+                (!deleted).then_some(reference),
+                before,
+                ref_typ,
+                triggered_by,
+            )
+            .await?;
+
+        let stop_jobs = def.stop_jobs();
+        let mut jobs: HashMap<_, _> = def
+            .jobs
+            .into_iter()
+            .map(|(id, job)| (id, Arc::new(job)))
+            .collect();
+
+        let mut scheduled = vec![];
 
-        let mut def_file = File::open(def_path).await?;
-        let mut def_data = vec![];
-        def_file.read_to_end(&mut def_data).await?;
+        if deleted {
+            for env in self.0.state.environments_for_ref(remote, reference_name) {
+                match env.on_stop.as_ref().and_then(|id| jobs.remove(id)) {
+                    Some(job) => {
+                        let job_id = env.on_stop.clone().unwrap_or_default();
+                        scheduled.push((job_id, job, Some(EnvironmentAction::Stop(env))));
+                    }
+                    None => {
+                        debug!(
+                            "Removing environment {} without stop job because ref {} has been deleted",
+                            env.name, ctx.ref_typ
+                        );
+                        self.0.state.remove_environment(&env.remote, &env.name)?;
+                    }
+                }
+            }
+        }
 
-        let def = Definition::parse(&def_data)?;
+        for (job_id, job) in jobs {
+            if stop_jobs.contains(&job_id) {
+                debug!("Skipping job {job_id} because it only stops environments");
+                continue;
+            }
 
-        for (job_id, job) in def.jobs {
-            if let Some(on) = &job.on {
-                if !on.matches(&ref_typ) {
+            match &job.on {
+                Some(on) if !on.matches(&ctx.ref_typ, deleted) => {
                     debug!(
                         "Skipping job {job_id} because ref does not match \
-                        ({on:?} != {ref_typ:?})",
+                        ({on:?} != {:?}, deleted: {deleted})",
+                        ctx.ref_typ
                     );
                     continue;
                 }
+                None if deleted => {
+                    debug!(
+                        "Skipping job {job_id} because ref {} has been deleted",
+                        ctx.ref_typ
+                    );
+                    continue;
+                }
+                _ => {}
             }
 
-            if matches!(job.await_result, Some(true)) {
-                self.run_job(&job, tmp_dir_path.to_path_buf(), &def.name, &repo, &ref_typ)
-                    .await?;
-            } else {
-                let tmp_dir_path_buf = tmp_dir_path.to_path_buf();
-                let def_name = def.name.clone();
-                let repo = repo.clone();
-                let ref_typ = ref_typ.clone();
-                let s = self.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = s
-                        .run_job(&job, tmp_dir_path_buf, &def_name, &repo, &ref_typ)
-                        .await
-                    {
-                        log::error!("Async job failed: {err}");
-                    }
-                });
-            }
+            let env = job.environment.as_ref().map(|env| {
+                EnvironmentAction::Deploy(state::Environment {
+                    name: env.name.clone(),
+                    remote: ctx.remote.clone(),
+                    reference: reference_name.to_string(),
+                    commit: ctx.commit.clone(),
+                    job: job_id.clone(),
+                    on_stop: env.on_stop.clone(),
+                    deployed_at: state::now(),
+                    stop_attempts: 0,
+                    stop_failed_at: None,
+                })
+            });
+
+            scheduled.push((job_id, job, env));
         }
 
+        self.schedule(Arc::new(ctx), scheduled).await;
+
         Ok(())
     }
 
+    /// Starts the given jobs as soon as all jobs they need have finished.
+    /// Awaited jobs are waited for before returning, all other jobs are
+    /// executed in the background.
+    async fn schedule(
+        &self,
+        ctx: Arc<RunContext>,
+        jobs: Vec<(String, Arc<Job>, Option<EnvironmentAction>)>,
+    ) {
+        let mut pending: HashMap<_, _> = jobs
+            .into_iter()
+            .map(|(id, job, env)| (id, (job, env)))
+            .collect();
+        let mut outcomes: HashMap<String, JobOutcome> = HashMap::new();
+        let mut awaited = vec![];
+
+        loop {
+            let ready: Vec<_> = pending
+                .iter()
+                .filter(|(_, (job, _))| {
+                    job.needs()
+                        .all(|need| outcomes.contains_key(need) || !pending.contains_key(need))
+                })
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            if ready.is_empty() {
+                break;
+            }
+
+            for job_id in ready {
+                let Some((job, env)) = pending.remove(&job_id) else {
+                    continue;
+                };
+
+                let needs = job
+                    .needs()
+                    .map(|need| (need.clone(), outcomes.get(need).cloned()))
+                    .collect();
+
+                let outcome = self
+                    .clone()
+                    .run_scheduled(ctx.clone(), job_id.clone(), job.clone(), env, needs)
+                    .boxed()
+                    .shared();
+                outcomes.insert(job_id, outcome.clone());
+
+                if matches!(job.await_result, Some(true)) {
+                    awaited.push(outcome);
+                } else {
+                    tokio::spawn(outcome);
+                }
+            }
+        }
+
+        for job_id in pending.keys() {
+            log::error!("Skipping job {job_id} because of a dependency cycle");
+        }
+
+        future::join_all(awaited).await;
+    }
+
+    async fn run_scheduled(
+        self,
+        ctx: Arc<RunContext>,
+        job_id: String,
+        job: Arc<Job>,
+        env: Option<EnvironmentAction>,
+        needs: Vec<(String, Option<JobOutcome>)>,
+    ) -> JobResult {
+        let mut inputs = HashMap::new();
+
+        for (need, outcome) in needs {
+            let result = match outcome {
+                Some(outcome) => outcome.await,
+                None => JobResult::from(JobState::Skipped),
+            };
+            if result.state != JobState::Success {
+                debug!("Skipping job {job_id} because needed job {need} did not succeed");
+                return JobState::Skipped.into();
+            }
+
+            inputs.extend(
+                result
+                    .outputs
+                    .iter()
+                    .map(|(k, v)| (format!("jobs.{need}.outputs.{k}"), v.clone())),
+            );
+            if let Some(artifact) = result.artifact {
+                inputs.insert(
+                    format!("jobs.{need}.artifact"),
+                    artifact.to_string_lossy().into_owned(),
+                );
+            }
+        }
+
+        match self.run_job(&ctx, &job_id, &job, env, inputs).await {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("Job {job_id} failed: {err}");
+                JobState::Failure.into()
+            }
+        }
+    }
+
+    /// Runs the stop job of all environments which have not been deployed
+    /// for longer than the given TTL.
+    pub async fn stop_idle_environments(&self, ttl: Duration) {
+        let now = state::now();
+        let deadline = now.saturating_sub(ttl.as_secs());
+
+        for env in self.0.state.environments() {
+            if env.deployed_at > deadline || !env.stop_due(now) {
+                continue;
+            }
+
+            debug!("Stopping idle environment {} ...", env.name);
+            let (remote, name) = (env.remote.clone(), env.name.clone());
+            let err = match self.stop_environment(env).await {
+                Ok(JobState::Success) => continue,
+                Ok(_) => "stop job did not succeed".to_string(),
+                Err(err) => err.to_string(),
+            };
+            log::error!("Stopping idle environment {name} failed: {err}");
+
+            // Failed attempts are recorded so that they are retried with a
+            // backoff instead of on every check.
+            match self.0.state.record_stop_failure(&remote, &name) {
+                Ok(attempts) if attempts >= state::MAX_STOP_ATTEMPTS => log::error!(
+                    "Giving up stopping environment {name} after {attempts} failed attempts"
+                ),
+                Ok(_) => {}
+                Err(err) => log::error!("Recording failed stop of {name} failed: {err}"),
+            }
+        }
+    }
+
+    async fn stop_environment(&self, env: state::Environment) -> Result<JobState> {
+        let ref_typ = env.reference.parse()?;
+        let (ctx, mut def) = self
+            .checkout(&env.remote, Some(&env.commit), None, ref_typ, "minicd")
+            .await?;
+
+        let Some((job_id, job)) = env
+            .on_stop
+            .as_ref()
+            .and_then(|id| def.jobs.remove_entry(id))
+        else {
+            self.0.state.remove_environment(&env.remote, &env.name)?;
+            return Ok(JobState::Success);
+        };
+
+        let result = self
+            .run_job(
+                &ctx,
+                &job_id,
+                &job,
+                Some(EnvironmentAction::Stop(env)),
+                HashMap::new(),
+            )
+            .await?;
+
+        Ok(result.state)
+    }
+
+    /// Re-runs the deploy job of the given environment against the last
+    /// successfully deployed commit which differs from the current one. The
+    /// token must belong to one of the configured API users, who is recorded
+    /// as the trigger of the rollback.
+    pub async fn rollback(
+        &self,
+        environment: &str,
+        remote: Option<&str>,
+        token: &str,
+    ) -> Result<state::Deployment> {
+        let Some(triggered_by) = self
+            .settings()
+            .api_tokens
+            .iter()
+            .find(|(_, t)| approval::token_eq(t, token))
+            .map(|(name, _)| name.clone())
+        else {
+            return Err(Error::RollbackUnauthorized(environment.into()));
+        };
+
+        let mut envs: Vec<_> = self
+            .0
+            .state
+            .environments()
+            .into_iter()
+            .filter(|env| env.name == environment && remote.is_none_or(|r| env.remote == r))
+            .collect();
+
+        let env = match envs.len() {
+            0 => return Err(Error::EnvironmentNotFound(environment.into())),
+            1 => envs.remove(0),
+            _ => return Err(Error::AmbiguousEnvironment(environment.into())),
+        };
+
+        let target = self
+            .0
+            .state
+            .rollback_target(&env)
+            .ok_or_else(|| Error::NoRollbackTarget(environment.into()))?;
+
+        let (ctx, mut def) = self
+            .checkout(
+                &target.remote,
+                Some(&target.commit),
+                None,
+                target.reference.parse()?,
+                &triggered_by,
+            )
+            .await?;
+
+        let job = def
+            .jobs
+            .remove(&target.job)
+            .ok_or_else(|| Error::JobNotFound(target.job.clone()))?;
+
+        let action = EnvironmentAction::Deploy(state::Environment {
+            name: env.name,
+            remote: target.remote.clone(),
+            reference: target.reference.clone(),
+            commit: target.commit.clone(),
+            job: target.job.clone(),
+            on_stop: job.environment.as_ref().and_then(|e| e.on_stop.clone()),
+            deployed_at: state::now(),
+            stop_attempts: 0,
+            stop_failed_at: None,
+        });
+
+        let result = self
+            .run_job(&ctx, &target.job, &job, Some(action), HashMap::new())
+            .await?;
+
+        match result.state {
+            JobState::Success => Ok(target),
+            _ => Err(Error::RollbackFailed(environment.into())),
+        }
+    }
+
+    /// Clones the given remote into a new temporary directory, checks out
+    /// the given commit (or the default branch if none is given) and reads
+    /// the definition file.
+    async fn checkout(
+        &self,
+        remote: &str,
+        commit: Option<&str>,
+        before: Option<&str>,
+        ref_typ: Ref,
+        triggered_by: &str,
+    ) -> Result<(RunContext, Definition)> {
+        let dir = TempDir::new().map_err(Error::TempDirCreationFailed)?;
+
+        let dir_path = dir.path().to_str().unwrap_or_default();
+        let repo = match &self.0.mirrors {
+            Some(mirrors) => mirrors.clone(remote, dir_path, commit).await?,
+            None => {
+                let (remote, dir, commit) = (
+                    remote.to_string(),
+                    dir_path.to_string(),
+                    commit.map(String::from),
+                );
+                git::blocking(move || Repository::fetch(remote, dir, commit.as_deref())).await?
+            }
+        };
+
+        let (repo, def_data) = git::blocking(move || {
+            let data = repo.read_file(".minicd")?;
+            Ok((repo, data))
+        })
+        .await?;
+        let def = Definition::parse(&def_data.ok_or(Error::NoDefinitionFile)?)?;
+
+        let mut options: git::CheckoutOptions =
+            def.checkout.clone().map(Into::into).unwrap_or_default();
+        if self.0.mirrors.is_some() {
+            // Shared clones of a mirror already contain the full history.
+            options.depth = None;
+        }
+        let before = before.map(String::from);
+        let (repo, commits) = git::blocking(move || {
+            let mut repo = repo;
+            repo.prepare(&options)?;
+            let commits = repo.commits_since(before.as_deref())?;
+            Ok((repo, commits))
+        })
+        .await?;
+
+        if let Some(artifacts) = &self.0.artifacts {
+            match artifacts.cleanup().await {
+                Ok(removed) if !removed.is_empty() => {
+                    debug!("Removed expired artifacts of runs {removed:?}");
+                    self.0.state.remove_artifacts(&removed)?;
+                }
+                Ok(_) => {}
+                Err(err) => log::error!("Removing expired artifacts failed: {err}"),
+            }
+        }
+
+        let id = self.0.state.create_run(state::Run {
+            id: 0,
+            name: def.name.clone(),
+            remote: remote.to_string(),
+            reference: ref_typ.to_string(),
+            commit: repo.get_ref().to_string(),
+            triggered_by: triggered_by.to_string(),
+            started_at: state::now(),
+            artifacts: vec![],
+        })?;
+
+        let commit_vars = commit_vars(&commits);
+        let env = commit_vars
+            .iter()
+            .map(|(k, v)| (format!("MINICD_{}", canonicalize_key(k)), v.clone()))
+            .collect();
+        let mut vars = ref_vars(&ref_typ);
+        vars.extend(commit_vars);
+
+        let settings = self.settings();
+        let ctx = RunContext {
+            id,
+            dir,
+            name: def.name.clone(),
+            remote: remote.to_string(),
+            commit: repo.get_ref().to_string(),
+            vars,
+            env,
+            definition_env: def.env.clone().unwrap_or_default(),
+            strict_placeholders: def.strict_placeholders.unwrap_or(false),
+            secrets: settings
+                .secrets
+                .for_repo(remote, settings.repo_dir.as_deref())
+                .scoped(&settings.secret_policies, remote, &ref_typ.to_string()),
+            settings,
+            commits,
+            ref_typ,
+            triggered_by: triggered_by.to_string(),
+        };
+
+        Ok((ctx, def))
+    }
+
     async fn run_job(
         &self,
+        ctx: &RunContext,
+        job_id: &str,
         job: &Job,
-        dir: PathBuf,
-        name: &str,
-        repo: &Repository,
-        ref_typ: &Ref,
-    ) -> Result<()> {
-        debug!("Starting job ...");
+        env: Option<EnvironmentAction>,
+        inputs: HashMap<String, String>,
+    ) -> Result<JobResult> {
+        debug!("Starting job {job_id} ...");
+
+        let secrets = ctx.job_secrets(job);
+        let mut vars = ctx.vars.clone();
+        let mut env_vars = ctx.env.clone();
+        env_vars.extend(inputs.iter().map(|(k, v)| (canonicalize_key(k), v.clone())));
+        vars.extend(inputs);
+
+        // Environment names may refer to outputs of needed jobs, so they are
+        // resolved not before the job is about to run.
+        let env = match env {
+            Some(EnvironmentAction::Deploy(mut env)) => {
+                env.name = ctx.replace(&env.name, &vars, &secrets)?;
+                Some(EnvironmentAction::Deploy(env))
+            }
+            env => env,
+        };
+        if let Some(EnvironmentAction::Deploy(env) | EnvironmentAction::Stop(env)) = &env {
+            vars.insert("environment.name".into(), env.name.clone());
+            env_vars.insert("MINICD_ENVIRONMENT".into(), env.name.clone());
+        }
+
+        // Values of the definition which are overridden by the job are not
+        // interpolated, so they can not fail the job.
+        let user_env = job.env.clone().unwrap_or_default();
+        let definition_env = ctx
+            .definition_env
+            .iter()
+            .filter(|(k, _)| !user_env.contains_key(*k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let definition_env = ctx.replace_env(definition_env, &vars, &secrets)?;
+        let user_env = ctx.replace_env(user_env, &vars, &secrets)?;
+
+        // Approvals required by the policies can not be removed by changing
+        // the definition.
+        let required = match &env {
+            Some(EnvironmentAction::Deploy(env)) => {
+                approval::required_approval(&ctx.settings.approval_policies, &ctx.remote, &env.name)
+            }
+            _ => None,
+        };
+        if let Some(approval) = required.as_ref().or(job.approval.as_ref()) {
+            match self
+                .await_approval(ctx, job_id, job, approval, &vars, &secrets)
+                .await?
+            {
+                Decision::Approved(by) => {
+                    log::info!("Job {job_id} has been approved by {by}");
+                }
+                Decision::Rejected(by) => {
+                    log::info!("Skipping job {job_id} because it has been rejected by {by}");
+                    return Ok(JobState::Skipped.into());
+                }
+                Decision::Expired => {
+                    log::info!("Skipping job {job_id} because its approval has expired");
+                    return Ok(JobState::Skipped.into());
+                }
+            }
+        }
+
         if let Some(notifies) = job.get_notify(JobState::Start) {
-            self.notify(&notifies, JobState::Start, name, repo, ref_typ, None)
+            let notification = ctx.notification(JobState::Start, job_id, None, None);
+            self.notify(&notifies, ctx, &vars, &secrets, &notification)
                 .await?;
         }
+        let started = Instant::now();
+
+        // The output file is placed outside of the checkout so that it can
+        // not be accidentally committed or collected by the job.
+        let output_dir = TempDir::new().map_err(Error::TempDirCreationFailed)?;
+        let output_path = output_dir.path().join("output");
+        File::create(&output_path)
+            .await
+            .map_err(Error::OutputFile)?;
+        env_vars.insert(
+            "MINICD_OUTPUT".into(),
+            output_path.to_string_lossy().into_owned(),
+        );
+
+        let caches = match &job.cache {
+            Some(caches) => {
+                self.restore_caches(ctx, job_id, caches.as_slice(), &vars, &secrets)
+                    .await?
+            }
+            None => vec![],
+        };
+
+        // Secret files are removed once the job has finished.
+        let mut secret_files = SecretFiles::new().map_err(Error::SecretFile)?;
+        let mut secrets_env = HashMap::new();
+        for secret in job.secrets.iter().flatten() {
+            let secrets = ctx.secrets.filter(&[secret.key()]).to_flat_map();
+            for (k, v) in secrets {
+                // Secrets tagged with `!file` in the secrets file are always
+                // passed as files.
+                let typ = match ctx.secrets.is_file(&k) {
+                    true => SecretType::File,
+                    false => secret.typ(),
+                };
+                match typ {
+                    SecretType::Env => {
+                        secrets_env.insert(to_env_key(&k), v);
+                    }
+                    SecretType::File => {
+                        let path = secret_files.write(&k, &v).map_err(Error::SecretFile)?;
+                        secrets_env.insert(
+                            format!("{}_FILE", to_env_key(&k)),
+                            path.to_string_lossy().into_owned(),
+                        );
+                    }
+                }
+            }
+        }
+        let env_vars = job_env(
+            definition_env,
+            user_env,
+            env_vars,
+            secrets_env,
+            standard_env(ctx, job_id),
+        );
+
+        let script_result = self.run_script(ctx, job, env_vars);
+        drop(secret_files);
 
-        match self.run_script(job, dir) {
+        let result = match script_result {
             Ok(std_out) => {
                 debug!("Job finished successful: {std_out}");
+
+                self.save_caches(ctx, job_id, caches).await;
+
+                let outputs = tokio::fs::read_to_string(&output_path)
+                    .await
+                    .map(|content| outputs::parse(&content))
+                    .map_err(Error::OutputFile)?;
+
+                let artifact = match &job.artifacts {
+                    Some(patterns) => self.collect_artifacts(ctx, job_id, patterns).await?,
+                    None => None,
+                };
+                vars.extend(
+                    outputs
+                        .iter()
+                        .map(|(k, v)| (format!("jobs.{job_id}.outputs.{k}"), v.clone())),
+                );
+
+                match env {
+                    Some(EnvironmentAction::Deploy(mut env)) => {
+                        debug!("Environment {} has been deployed", env.name);
+                        env.deployed_at = state::now();
+                        self.0.state.record_deployment(env, &ctx.triggered_by)?;
+                    }
+                    Some(EnvironmentAction::Stop(env)) => {
+                        debug!("Environment {} has been stopped", env.name);
+                        self.0.state.remove_environment(&env.remote, &env.name)?;
+                    }
+                    None => {}
+                }
+
                 if let Some(notifies) = job.get_notify(JobState::Success) {
-                    self.notify(
-                        &notifies,
+                    let notification = ctx.notification(
                         JobState::Success,
-                        name,
-                        repo,
-                        ref_typ,
+                        job_id,
+                        Some(started.elapsed()),
                         Some(&std_out),
-                    )
-                    .await?;
+                    );
+                    self.notify(&notifies, ctx, &vars, &secrets, &notification)
+                        .await?;
+                }
+
+                JobResult {
+                    state: JobState::Success,
+                    outputs: Arc::new(outputs),
+                    artifact,
                 }
             }
             Err(err) => {
                 debug!("Job failed: {err}");
                 if let Some(notifies) = job.get_notify(JobState::Failure) {
-                    self.notify(
-                        &notifies,
+                    let err = err.to_string();
+                    let notification = ctx.notification(
                         JobState::Failure,
-                        name,
-                        repo,
-                        ref_typ,
-                        Some(&err.to_string()),
-                    )
-                    .await?;
+                        job_id,
+                        Some(started.elapsed()),
+                        Some(&err),
+                    );
+                    self.notify(&notifies, ctx, &vars, &secrets, &notification)
+                        .await?;
+                }
+
+                JobState::Failure.into()
+            }
+        };
+
+        Ok(result)
+    }
+
+    /// Restores the given caches into the checkout. Returns the caches
+    /// together with their resolved keys which were not found in the cache
+    /// store and thus need to be saved after the job has finished.
+    async fn restore_caches<'a>(
+        &self,
+        ctx: &RunContext,
+        job_id: &str,
+        caches: &'a [Cache],
+        vars: &HashMap<String, String>,
+        secrets: &SecretManager,
+    ) -> Result<Vec<(String, &'a Cache)>> {
+        let Some(store) = &self.0.cache else {
+            log::warn!("Not restoring caches of job {job_id}: cache store has not been configured");
+            return Ok(vec![]);
+        };
+
+        let mut missed = vec![];
+        for cache in caches {
+            let mut key = ctx.replace(&cache.key, vars, secrets)?;
+            if let Some(files) = &cache.hash_files {
+                match cache::hash_files(ctx.dir.path(), files) {
+                    Ok(hash) => key = format!("{key}-{hash}"),
+                    Err(err) => {
+                        log::warn!("Hashing cache files of job {job_id} failed: {err}");
+                        continue;
+                    }
                 }
             }
+
+            match store.restore(&ctx.remote, &key, ctx.dir.path()).await {
+                Ok(true) => debug!("Restored cache {key} for job {job_id}"),
+                Ok(false) => {
+                    debug!("Cache miss for key {key} in job {job_id}");
+                    missed.push((key, cache));
+                }
+                Err(err) => log::warn!("Restoring cache {key} of job {job_id} failed: {err}"),
+            }
         }
 
-        Ok(())
+        Ok(missed)
+    }
+
+    async fn save_caches(&self, ctx: &RunContext, job_id: &str, caches: Vec<(String, &Cache)>) {
+        let Some(store) = &self.0.cache else {
+            return;
+        };
+
+        for (key, cache) in caches {
+            match store
+                .save(&ctx.remote, &key, ctx.dir.path(), &cache.paths)
+                .await
+            {
+                Ok(true) => debug!("Saved cache {key} of job {job_id}"),
+                Ok(false) => debug!("Not saving cache {key} of job {job_id}: no paths exist"),
+                Err(err) => log::warn!("Saving cache {key} of job {job_id} failed: {err}"),
+            }
+        }
+    }
+
+    /// Archives the files of the checkout matching the given patterns into
+    /// the artifact store and records the archive with the run.
+    async fn collect_artifacts(
+        &self,
+        ctx: &RunContext,
+        job_id: &str,
+        patterns: &[String],
+    ) -> Result<Option<PathBuf>> {
+        let Some(artifacts) = &self.0.artifacts else {
+            log::warn!(
+                "Not collecting artifacts of job {job_id}: artifact store has not been configured"
+            );
+            return Ok(None);
+        };
+
+        let Some((path, size)) = artifacts
+            .collect(ctx.id, job_id, ctx.dir.path(), patterns)
+            .await?
+        else {
+            log::warn!("No artifacts found for job {job_id} matching {patterns:?}");
+            return Ok(None);
+        };
+
+        debug!(
+            "Collected artifacts of job {job_id} into {}",
+            path.display()
+        );
+        self.0.state.add_artifact(
+            ctx.id,
+            state::Artifact {
+                name: job_id.to_string(),
+                size,
+                created_at: state::now(),
+            },
+        )?;
+
+        Ok(Some(path))
+    }
+
+    /// Registers a pending approval for the given job and waits until it
+    /// has been decided on or the approval timeout has passed.
+    async fn await_approval(
+        &self,
+        ctx: &RunContext,
+        job_id: &str,
+        job: &Job,
+        approval: &Approval,
+        vars: &HashMap<String, String>,
+        secrets: &SecretManager,
+    ) -> Result<Decision> {
+        // Approvers are resolved against the tokens configured on the
+        // server, so that the definition can not grant approval rights.
+        let approvers: Vec<_> = approval
+            .approvers
+            .iter()
+            .filter_map(|name| match ctx.settings.api_tokens.get(name) {
+                Some(token) if !token.is_empty() => Some((name.clone(), token.clone())),
+                _ => {
+                    log::warn!("Ignoring approver {name} of job {job_id} without API token");
+                    None
+                }
+            })
+            .collect();
+
+        if approvers.is_empty() {
+            log::warn!("Job {job_id} requires approval but has no valid approvers");
+            return Ok(Decision::Expired);
+        }
+
+        let requested_at = state::now();
+        let (request, decision) = self.0.approvals.request(
+            PendingApproval {
+                id: 0,
+                name: ctx.name.clone(),
+                remote: ctx.remote.clone(),
+                reference: ctx.ref_typ.to_string(),
+                commit: ctx.commit.clone(),
+                job: job_id.to_string(),
+                requested_at,
+                expires_at: approval.timeout_secs.map(|t| requested_at + t),
+            },
+            approvers,
+        );
+
+        let id = request.id;
+        debug!("Job {job_id} is waiting for approval {id} ...");
+        if let Some(notifies) = job.get_notify(JobState::Waiting) {
+            let context = format!("Approval ID: {id}");
+            let notification = ctx.notification(JobState::Waiting, job_id, None, Some(&context));
+            self.notify(&notifies, ctx, vars, secrets, &notification)
+                .await?;
+        }
+
+        let decision = match approval.timeout_secs {
+            Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), decision)
+                .await
+                .ok(),
+            None => Some(decision.await),
+        };
+
+        Ok(decision.and_then(|d| d.ok()).unwrap_or(Decision::Expired))
     }
 
     async fn notify(
         &self,
         notifies: &[&Notify],
-        state: JobState,
-        name: &str,
-        repo: &Repository,
-        ref_typ: &Ref,
-        context: Option<&str>,
+        ctx: &RunContext,
+        vars: &HashMap<String, String>,
+        secrets: &SecretManager,
+        notification: &Notification<'_>,
     ) -> Result<()> {
+        let state = notification.state;
+        let context = notification.context;
         for target in notifies.iter().flat_map(|n| &n.to) {
             match target {
                 NotifyTarget::EMail { address } => {
-                    let Some(mailer) = &self.0.mailer else {
+                    let Some(mailer) = &ctx.settings.mailer else {
                         log::warn!("mail notification: mailer has not been configured");
                         return Ok(());
                     };
 
-                    let address = self.0.secrets.replace(address);
-                    let subject = state.get_subject(name, ref_typ);
-                    let body = state.get_body(name, ref_typ, repo.get_ref(), context);
+                    let address = ctx.replace(address, vars, secrets)?;
+                    let subject = state.get_subject(&ctx.name, &ctx.ref_typ);
+                    let body = state.get_body(&ctx.name, &ctx.ref_typ, &ctx.commits, context);
                     mailer.send(&address, subject, body).await?;
                 }
                 NotifyTarget::WebHook {
@@ -161,7 +1077,7 @@ impl Runner {
                     method,
                     headers,
                 } => {
-                    let url = self.0.secrets.replace(url);
+                    let url = ctx.replace(url, vars, secrets)?;
                     let method = method.clone().unwrap_or_else(|| "GET".into()).parse()?;
 
                     let mut header_map = HeaderMap::new();
@@ -177,23 +1093,84 @@ impl Runner {
                         .await?
                         .error_for_status()?;
                 }
+                NotifyTarget::Slack { url } => {
+                    let url = ctx.replace(url, vars, secrets)?;
+                    notifications::send_slack(&url, notification).await?;
+                }
+                NotifyTarget::Discord { url } => {
+                    let url = ctx.replace(url, vars, secrets)?;
+                    notifications::send_discord(&url, notification).await?;
+                }
+                NotifyTarget::Matrix {
+                    homeserver,
+                    room,
+                    access_token,
+                } => {
+                    let homeserver = ctx.replace(homeserver, vars, secrets)?;
+                    let room = ctx.replace(room, vars, secrets)?;
+                    let access_token = ctx.replace(access_token, vars, secrets)?;
+                    notifications::send_matrix(&homeserver, &room, &access_token, notification)
+                        .await?;
+                }
+                NotifyTarget::Telegram {
+                    token,
+                    chat_id,
+                    api_url,
+                } => {
+                    let api_url = api_url
+                        .as_ref()
+                        .map(|u| ctx.replace(u, vars, secrets))
+                        .transpose()?;
+                    let token = ctx.replace(token, vars, secrets)?;
+                    let chat_id = ctx.replace(chat_id, vars, secrets)?;
+                    notifications::send_telegram(
+                        api_url.as_deref(),
+                        &token,
+                        &chat_id,
+                        notification,
+                    )
+                    .await?;
+                }
+                NotifyTarget::Ntfy {
+                    server,
+                    topic,
+                    token,
+                } => {
+                    let server = server
+                        .as_ref()
+                        .map(|s| ctx.replace(s, vars, secrets))
+                        .transpose()?;
+                    let topic = ctx.replace(topic, vars, secrets)?;
+                    let token = token
+                        .as_ref()
+                        .map(|t| ctx.replace(t, vars, secrets))
+                        .transpose()?;
+                    notifications::send_ntfy(
+                        server.as_deref(),
+                        &topic,
+                        token.as_deref(),
+                        notification,
+                    )
+                    .await?;
+                }
+                NotifyTarget::Gotify { url, token } => {
+                    let url = ctx.replace(url, vars, secrets)?;
+                    let token = ctx.replace(token, vars, secrets)?;
+                    notifications::send_gotify(&url, &token, notification).await?;
+                }
             }
         }
         Ok(())
     }
 
-    fn run_script(&self, job: &Job, dir: PathBuf) -> Result<String> {
+    fn run_script(
+        &self,
+        ctx: &RunContext,
+        job: &Job,
+        env_vars: HashMap<String, String>,
+    ) -> Result<String> {
         let mut options = ScriptOptions::new();
-        options.working_directory = Some(dir);
-
-        let env_vars = self
-            .0
-            .secrets
-            .to_flat_map()
-            .iter()
-            .map(|(k, v)| (to_env_key(k), v.clone()))
-            .collect();
-
+        options.working_directory = Some(ctx.dir.path().to_path_buf());
         options.env_vars = Some(env_vars);
 
         if let Some(shell) = &job.shell {
@@ -208,12 +1185,24 @@ impl Runner {
             }
         }
 
+        // The output is masked here so that secret values neither end up in
+        // the logs nor in any notification.
         let (code, std_out, std_err) = run_script::run(&job.run, &vec![], &options)?;
         if code != 0 {
-            return Err((code, std_err).into());
+            return Err((code, ctx.settings.secrets.mask(&std_err)).into());
         }
 
-        Ok(std_out)
+        Ok(ctx.settings.secrets.mask(&std_out))
+    }
+}
+
+impl From<JobState> for JobResult {
+    fn from(state: JobState) -> Self {
+        Self {
+            state,
+            outputs: Arc::default(),
+            artifact: None,
+        }
     }
 }
 
@@ -222,17 +1211,40 @@ impl JobState {
         format!("{}: {name} @ {ref_typ}", self.get_subject_prefix())
     }
 
-    fn get_body(&self, name: &str, ref_typ: &Ref, rf: &str, context: Option<&str>) -> String {
+    fn get_body(
+        &self,
+        name: &str,
+        ref_typ: &Ref,
+        commits: &[git::CommitInfo],
+        context: Option<&str>,
+    ) -> String {
+        let commit = commit_details(commits);
         match self {
             JobState::Start => format!(
                 "A job has been started on project {name}.\n\
                 \n\
-                Reference: {ref_typ} ({rf})\n"
+                Reference: {ref_typ}\n\
+                {commit}"
+            ),
+            JobState::Waiting => format!(
+                "A job on project {name} is waiting for approval.\n\
+                \n\
+                Reference: {ref_typ}\n\
+                {commit}\
+                {}\n",
+                context.unwrap_or_default()
+            ),
+            JobState::Skipped => format!(
+                "A job on project {name} has been skipped.\n\
+                \n\
+                Reference: {ref_typ}\n\
+                {commit}"
             ),
             JobState::Success => format!(
                 "A job on project {name} has finished successful.\n\
                 \n\
-                Reference: {ref_typ} ({rf})\n\
+                Reference: {ref_typ}\n\
+                {commit}\
                 \n\
                 Logs:\n\
                 {}\n",
@@ -241,7 +1253,8 @@ impl JobState {
             JobState::Failure => format!(
                 "A job on project {name} has failed.\n\
                 \n\
-                Reference: {ref_typ} ({rf})\n\
+                Reference: {ref_typ}\n\
+                {commit}\
                 \n\
                 Error:\n\
                 {}\n",
@@ -253,12 +1266,302 @@ impl JobState {
     fn get_subject_prefix(&self) -> &'static str {
         match self {
             Self::Start => "Job processing started",
+            Self::Waiting => "Job waiting for approval",
+            Self::Skipped => "Job skipped",
             Self::Success => "Job finished successful",
             Self::Failure => "Job failed",
         }
     }
 }
 
+fn ref_vars(ref_typ: &Ref) -> HashMap<String, String> {
+    let mut vars = HashMap::from([("ref.name".to_string(), ref_typ.to_string())]);
+    match ref_typ {
+        Ref::Branch(branch) => vars.insert("ref.branch".into(), branch.clone()),
+        Ref::Tag(tag) => vars.insert("ref.tag".into(), tag.clone()),
+    };
+    vars
+}
+
+/// Assembles the environment of a job from the given layers, where later
+/// layers take precedence: the job overrides the definition, and neither
+/// can override the variables set by minicd, the secrets or the standard
+/// variables.
+fn job_env(
+    definition: HashMap<String, String>,
+    job: HashMap<String, String>,
+    minicd: HashMap<String, String>,
+    secrets: HashMap<String, String>,
+    standard: HashMap<String, String>,
+) -> HashMap<String, String> {
+    [definition, job, minicd, secrets, standard]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Returns the `MINICD_*` variables describing the run which are passed
+/// to every job.
+fn standard_env(ctx: &RunContext, job_id: &str) -> HashMap<String, String> {
+    [
+        ("MINICD", "true".to_string()),
+        ("MINICD_REPO", ctx.remote.clone()),
+        ("MINICD_REF", ctx.ref_typ.to_string()),
+        ("MINICD_REF_TYPE", ctx.ref_typ.kind().to_string()),
+        ("MINICD_REF_NAME", ctx.ref_typ.name().to_string()),
+        ("MINICD_SHA", ctx.commit.clone()),
+        ("MINICD_RUN_ID", ctx.id.to_string()),
+        ("MINICD_JOB_ID", job_id.to_string()),
+        (
+            "MINICD_WORKSPACE",
+            ctx.dir.path().to_string_lossy().into_owned(),
+        ),
+        ("MINICD_DEFINITION_NAME", ctx.name.clone()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+/// Formats the checked out commit and, if there are more, the other
+/// commits of the push for notification bodies.
+fn commit_details(commits: &[git::CommitInfo]) -> String {
+    let Some(head) = commits.first() else {
+        return String::new();
+    };
+
+    let mut details = format!(
+        "Commit: {} {}\n\
+        Author: {} <{}>\n",
+        head.sha, head.subject, head.author.name, head.author.email
+    );
+    if commits.len() > 1 {
+        details.push_str("\nCommits:\n");
+        for c in commits {
+            details.push_str(&format!(
+                "  {} {} ({})\n",
+                c.short_sha, c.subject, c.author.name
+            ));
+        }
+    }
+    details
+}
+
+/// Exposes the given commits of a push, newest first, as `commit.*`
+/// placeholders.
+fn commit_vars(commits: &[git::CommitInfo]) -> HashMap<String, String> {
+    let Some(head) = commits.first() else {
+        return HashMap::new();
+    };
+
+    let list: Vec<_> = commits
+        .iter()
+        .map(|c| format!("{} {}", c.short_sha, c.subject))
+        .collect();
+
+    [
+        ("commit.sha", head.sha.clone()),
+        ("commit.short_sha", head.short_sha.clone()),
+        ("commit.author.name", head.author.name.clone()),
+        ("commit.author.email", head.author.email.clone()),
+        ("commit.committer.name", head.committer.name.clone()),
+        ("commit.committer.email", head.committer.email.clone()),
+        ("commit.timestamp", head.timestamp.to_string()),
+        ("commit.subject", head.subject.clone()),
+        ("commit.body", head.body.clone()),
+        ("commit.count", commits.len().to_string()),
+        ("commit.list", list.join("\n")),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
 fn to_env_key(key: &str) -> String {
     format!("SECRETS_{}", key.to_uppercase().replace('.', "_"))
 }
+
+/// Turns a dotted key into an environment variable name, e.g.
+/// `jobs.build.outputs.image-tag` into `JOBS_BUILD_OUTPUTS_IMAGE_TAG`.
+fn canonicalize_key(key: &str) -> String {
+    key.to_uppercase().replace(['.', '-'], "_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            secrets: SecretManager::empty(),
+            secret_policies: vec![],
+            approval_policies: vec![],
+            mailer: None,
+            public_url: None,
+            repo_dir: None,
+            api_tokens: HashMap::from([("alice".into(), "alice-token".into())]),
+        }
+    }
+
+    fn runner(state: StateStore) -> Runner {
+        Runner::new(settings(), state, None, None, None)
+    }
+
+    fn context() -> RunContext {
+        RunContext {
+            id: 42,
+            dir: TempDir::new().unwrap(),
+            name: "app".into(),
+            remote: "/repos/app.git".into(),
+            commit: "abc123".into(),
+            commits: vec![],
+            ref_typ: Ref::Tag("v1.0.0".into()),
+            triggered_by: "push".into(),
+            vars: HashMap::new(),
+            env: HashMap::new(),
+            settings: Arc::new(settings()),
+            secrets: SecretManager::empty(),
+            definition_env: HashMap::new(),
+            strict_placeholders: false,
+        }
+    }
+
+    fn deploy(state: &StateStore, name: &str, commit: &str) {
+        let env = state::Environment {
+            name: name.into(),
+            remote: "/repos/app.git".into(),
+            reference: "refs/heads/main".into(),
+            commit: commit.into(),
+            job: "deploy".into(),
+            on_stop: None,
+            deployed_at: state::now(),
+            stop_attempts: 0,
+            stop_failed_at: None,
+        };
+        state.record_deployment(env, "bob").unwrap();
+    }
+
+    #[tokio::test]
+    async fn rollback() {
+        let state = StateStore::in_memory();
+        deploy(&state, "production", "a");
+        let runner = runner(state);
+
+        for token in ["", "bob-token", "alice-token "] {
+            assert!(matches!(
+                runner.rollback("production", None, token).await,
+                Err(Error::RollbackUnauthorized(_))
+            ));
+        }
+        assert!(matches!(
+            runner.rollback("staging", None, "alice-token").await,
+            Err(Error::EnvironmentNotFound(_))
+        ));
+        assert!(matches!(
+            runner.rollback("production", None, "alice-token").await,
+            Err(Error::NoRollbackTarget(_))
+        ));
+        assert_eq!(1, runner.deployments(None, "production").len());
+    }
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_precedence() {
+        let vars = job_env(
+            env(&[
+                ("A", "definition"),
+                ("B", "definition"),
+                ("MINICD_SHA", "x"),
+            ]),
+            env(&[
+                ("B", "job"),
+                ("C", "job"),
+                ("MINICD_ENVIRONMENT", "x"),
+                ("SECRETS_TOKEN", "x"),
+                ("SECRETS_KEY_FILE", "x"),
+            ]),
+            env(&[("MINICD_ENVIRONMENT", "production")]),
+            env(&[
+                ("SECRETS_TOKEN", "secret"),
+                ("SECRETS_KEY_FILE", "/tmp/key"),
+            ]),
+            env(&[("MINICD_SHA", "abc123")]),
+        );
+
+        assert_eq!(
+            env(&[
+                ("A", "definition"),
+                ("B", "job"),
+                ("C", "job"),
+                ("MINICD_ENVIRONMENT", "production"),
+                ("MINICD_SHA", "abc123"),
+                ("SECRETS_TOKEN", "secret"),
+                ("SECRETS_KEY_FILE", "/tmp/key"),
+            ]),
+            vars
+        );
+    }
+
+    #[test]
+    fn standard_vars() {
+        let ctx = context();
+        let vars = job_env(
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            standard_env(&ctx, "build"),
+        );
+
+        let workspace = ctx.dir.path().to_string_lossy();
+        for (k, v) in [
+            ("MINICD", "true"),
+            ("MINICD_REPO", "/repos/app.git"),
+            ("MINICD_REF", "refs/tags/v1.0.0"),
+            ("MINICD_REF_TYPE", "tag"),
+            ("MINICD_REF_NAME", "v1.0.0"),
+            ("MINICD_SHA", "abc123"),
+            ("MINICD_RUN_ID", "42"),
+            ("MINICD_JOB_ID", "build"),
+            ("MINICD_WORKSPACE", &workspace),
+            ("MINICD_DEFINITION_NAME", "app"),
+        ] {
+            assert_eq!(Some(v), vars.get(k).map(String::as_str), "{k}");
+        }
+        assert_eq!(10, vars.len());
+    }
+
+    #[test]
+    fn job_secrets() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("secrets.yml");
+        std::fs::write(&path, "docker:\n  password: hunter2\ndeploy_key: key\n").unwrap();
+        let mut ctx = context();
+        ctx.secrets = SecretManager::new(&path, None).unwrap();
+
+        let job: Job = serde_yaml::from_str("run: 'true'\nsecrets: [docker]").unwrap();
+        let secrets = ctx.job_secrets(&job);
+        let vars = HashMap::new();
+        assert_eq!(
+            "hunter2 {{ deploy_key }}",
+            ctx.replace("{{ docker.password }} {{ deploy_key }}", &vars, &secrets)
+                .unwrap()
+        );
+
+        ctx.strict_placeholders = true;
+        assert!(matches!(
+            ctx.replace("{{ deploy_key }}", &vars, &secrets),
+            Err(Error::Placeholder(_))
+        ));
+
+        let job: Job = serde_yaml::from_str("run: 'true'").unwrap();
+        assert!(ctx
+            .replace("{{ docker.password }}", &vars, &ctx.job_secrets(&job))
+            .is_err());
+    }
+}
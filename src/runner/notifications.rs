@@ -0,0 +1,714 @@
+use super::error::{Error, Result};
+use crate::{
+    definition::{JobState, Ref},
+    git::CommitInfo,
+};
+use reqwest::{RequestBuilder, Url};
+use serde_json::{json, Value};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The number of lines of the job log included in chat messages.
+const LOG_TAIL_LINES: usize = 20;
+/// The maximum number of characters of the job log included in chat
+/// messages, which keeps messages below the size limits of the services.
+const LOG_TAIL_CHARS: usize = 1000;
+/// The maximum length of message titles supported by all services.
+const MAX_TITLE_CHARS: usize = 250;
+
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+const NTFY_SERVER: &str = "https://ntfy.sh";
+
+/// How urgently users should be notified about an event.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Priority {
+    Low,
+    Default,
+    High,
+}
+
+/// A job event sent to chat notification targets.
+pub struct Notification<'a> {
+    pub state: JobState,
+    pub project: &'a str,
+    pub job: &'a str,
+    pub reference: &'a Ref,
+    pub commit: Option<&'a CommitInfo>,
+    pub duration: Option<Duration>,
+    /// The log of the finished job or additional details of the event.
+    pub context: Option<&'a str>,
+    /// A link to the run in the API.
+    pub url: Option<String>,
+}
+
+impl Notification<'_> {
+    fn title(&self) -> String {
+        truncate_start(
+            &format!(
+                "{}: {} / {}",
+                self.state.get_subject_prefix(),
+                self.project,
+                self.job
+            ),
+            MAX_TITLE_CHARS,
+        )
+    }
+
+    /// Failures and jobs waiting for approval need attention, while
+    /// started and skipped jobs are merely informational.
+    fn priority(&self) -> Priority {
+        match self.state {
+            JobState::Failure | JobState::Waiting => Priority::High,
+            JobState::Success => Priority::Default,
+            JobState::Start | JobState::Skipped => Priority::Low,
+        }
+    }
+
+    fn color(&self) -> u32 {
+        match self.state {
+            JobState::Start => 0x1d9bd1,
+            JobState::Waiting => 0xecb22e,
+            JobState::Success => 0x2eb67d,
+            JobState::Failure => 0xe01e5a,
+            JobState::Skipped => 0x9e9e9e,
+        }
+    }
+
+    /// The fields shown in messages as name and value pairs.
+    fn fields(&self) -> Vec<(&'static str, String, bool)> {
+        let mut fields = vec![
+            ("Project", self.project.to_string(), true),
+            ("Reference", self.reference.to_string(), true),
+        ];
+        if let Some(commit) = self.commit {
+            let commit = match commit.subject.is_empty() {
+                true => commit.short_sha.clone(),
+                false => format!("{} {}", commit.short_sha, commit.subject),
+            };
+            fields.push(("Commit", commit, false));
+        }
+        if let Some(duration) = self.duration {
+            fields.push(("Duration", format_duration(duration), true));
+        }
+        fields
+    }
+
+    /// The fields and the log tail as plain text.
+    fn text(&self) -> String {
+        let mut text: Vec<_> = self
+            .fields()
+            .into_iter()
+            .map(|(name, value, _)| format!("{name}: {value}"))
+            .collect();
+        if let Some(context) = self.context.map(str::trim_end).filter(|c| !c.is_empty()) {
+            text.push(String::new());
+            text.push(log_tail(context));
+        }
+        text.join("\n")
+    }
+
+    /// The title, the fields, the log tail and the link to the run as HTML.
+    fn html(&self, line_break: &str) -> String {
+        let mut html = vec![format!("<b>{}</b>", html_escape(&self.title()))];
+        html.extend(
+            self.fields()
+                .into_iter()
+                .map(|(name, value, _)| format!("{name}: {}", html_escape(&value))),
+        );
+        if let Some(context) = self.context.map(str::trim_end).filter(|c| !c.is_empty()) {
+            html.push(format!("<pre>{}</pre>", html_escape(&log_tail(context))));
+        }
+        if let Some(url) = &self.url {
+            html.push(format!("<a href=\"{}\">View run</a>", html_escape(url)));
+        }
+        html.join(line_break)
+    }
+
+    /// The last lines of the log, wrapped in a code block.
+    fn log_block(&self) -> Option<String> {
+        let context = self.context?.trim_end();
+        if context.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "```\n{}\n```",
+            log_tail(context).replace("```", "'''")
+        ))
+    }
+}
+
+/// Posts the notification to a Slack incoming webhook.
+pub async fn send_slack(url: &str, notification: &Notification<'_>) -> Result<()> {
+    post(url, &slack_payload(notification)).await
+}
+
+/// Posts the notification to a Discord webhook.
+pub async fn send_discord(url: &str, notification: &Notification<'_>) -> Result<()> {
+    post(url, &discord_payload(notification)).await
+}
+
+/// Sends the notification to a Matrix room using the client-server API.
+pub async fn send_matrix(
+    homeserver: &str,
+    room: &str,
+    access_token: &str,
+    notification: &Notification<'_>,
+) -> Result<()> {
+    static TXN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // Transaction IDs must be unique per access token, otherwise the
+    // homeserver treats the message as a retransmission.
+    let txn_id = format!(
+        "minicd-{}-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+        TXN_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let mut url =
+        Url::parse(homeserver).map_err(|_| Error::InvalidNotificationUrl(homeserver.into()))?;
+    url.path_segments_mut()
+        .map_err(|_| Error::InvalidNotificationUrl(homeserver.into()))?
+        .pop_if_empty()
+        .extend([
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            room,
+            "send",
+            "m.room.message",
+            &txn_id,
+        ]);
+
+    let request = reqwest::Client::default()
+        .put(url)
+        .bearer_auth(access_token);
+    send(request, &matrix_payload(notification)).await
+}
+
+/// Sends the notification to a Telegram chat using the bot API.
+pub async fn send_telegram(
+    api_url: Option<&str>,
+    token: &str,
+    chat_id: &str,
+    notification: &Notification<'_>,
+) -> Result<()> {
+    let api_url = api_url.unwrap_or(TELEGRAM_API_URL).trim_end_matches('/');
+    let request = reqwest::Client::default().post(format!("{api_url}/bot{token}/sendMessage"));
+    send(request, &telegram_payload(chat_id, notification)).await
+}
+
+/// Publishes the notification to an ntfy topic.
+pub async fn send_ntfy(
+    server: Option<&str>,
+    topic: &str,
+    token: Option<&str>,
+    notification: &Notification<'_>,
+) -> Result<()> {
+    let server = server.unwrap_or(NTFY_SERVER).trim_end_matches('/');
+    let mut request = reqwest::Client::default().post(server);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    send(request, &ntfy_payload(topic, notification)).await
+}
+
+/// Sends the notification to a Gotify server.
+pub async fn send_gotify(url: &str, token: &str, notification: &Notification<'_>) -> Result<()> {
+    let request = reqwest::Client::default()
+        .post(format!("{}/message", url.trim_end_matches('/')))
+        .header("X-Gotify-Key", token);
+    send(request, &gotify_payload(notification)).await
+}
+
+async fn post(url: &str, payload: &Value) -> Result<()> {
+    send(reqwest::Client::default().post(url), payload).await
+}
+
+/// Sends the payload as JSON. The URL is removed from errors, as it may
+/// contain tokens like the ones of Telegram bots or Slack webhooks.
+async fn send(request: RequestBuilder, payload: &Value) -> Result<()> {
+    request
+        .json(payload)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(reqwest::Error::without_url)?;
+    Ok(())
+}
+
+fn slack_payload(n: &Notification<'_>) -> Value {
+    let title = slack_escape(&n.title());
+    let fields: Vec<_> = n
+        .fields()
+        .into_iter()
+        .map(|(name, value, short)| {
+            json!({
+                "title": name,
+                "value": slack_escape(&value),
+                "short": short,
+            })
+        })
+        .collect();
+
+    let mut attachment = json!({
+        "fallback": title,
+        "color": format!("#{:06x}", n.color()),
+        "title": title,
+        "fields": fields,
+        "mrkdwn_in": ["text"],
+    });
+    if let Some(url) = &n.url {
+        attachment["title_link"] = url.clone().into();
+    }
+    if let Some(log) = n.log_block() {
+        attachment["text"] = slack_escape(&log).into();
+    }
+
+    json!({
+        "text": title,
+        "attachments": [attachment],
+    })
+}
+
+fn discord_payload(n: &Notification<'_>) -> Value {
+    let fields: Vec<_> = n
+        .fields()
+        .into_iter()
+        .map(|(name, value, inline)| {
+            json!({
+                "name": name,
+                "value": value,
+                "inline": inline,
+            })
+        })
+        .collect();
+
+    let mut embed = json!({
+        "title": n.title(),
+        "color": n.color(),
+        "fields": fields,
+    });
+    if let Some(url) = &n.url {
+        embed["url"] = url.clone().into();
+    }
+    if let Some(log) = n.log_block() {
+        embed["description"] = log.into();
+    }
+
+    json!({ "embeds": [embed] })
+}
+
+fn matrix_payload(n: &Notification<'_>) -> Value {
+    // Notices are meant for bots and usually do not trigger notifications
+    // in clients, so they are only used for events of lower priority.
+    let msgtype = match n.priority() {
+        Priority::High => "m.text",
+        Priority::Default | Priority::Low => "m.notice",
+    };
+
+    let mut body = format!("{}\n{}", n.title(), n.text());
+    if let Some(url) = &n.url {
+        body.push_str(&format!("\n{url}"));
+    }
+
+    json!({
+        "msgtype": msgtype,
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": n.html("<br>"),
+    })
+}
+
+fn telegram_payload(chat_id: &str, n: &Notification<'_>) -> Value {
+    json!({
+        "chat_id": chat_id,
+        "text": n.html("\n"),
+        "parse_mode": "HTML",
+        "disable_web_page_preview": true,
+        "disable_notification": n.priority() == Priority::Low,
+    })
+}
+
+fn ntfy_payload(topic: &str, n: &Notification<'_>) -> Value {
+    let (priority, tag) = match (n.priority(), n.state) {
+        (Priority::High, JobState::Failure) => (4, "x"),
+        (Priority::High, _) => (4, "hourglass"),
+        (Priority::Default, _) => (3, "white_check_mark"),
+        (Priority::Low, _) => (2, "information_source"),
+    };
+
+    let mut payload = json!({
+        "topic": topic,
+        "title": n.title(),
+        "message": n.text(),
+        "priority": priority,
+        "tags": [tag],
+    });
+    if let Some(url) = &n.url {
+        payload["click"] = url.clone().into();
+    }
+    payload
+}
+
+fn gotify_payload(n: &Notification<'_>) -> Value {
+    let priority = match n.priority() {
+        Priority::High => 8,
+        Priority::Default => 5,
+        Priority::Low => 2,
+    };
+
+    let mut payload = json!({
+        "title": n.title(),
+        "message": n.text(),
+        "priority": priority,
+    });
+    if let Some(url) = &n.url {
+        payload["extras"] = json!({
+            "client::notification": { "click": { "url": url } },
+        });
+    }
+    payload
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes the characters which have a special meaning in Slack messages.
+fn slack_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Returns the last lines of the log, limited to [`LOG_TAIL_CHARS`].
+fn log_tail(log: &str) -> String {
+    let lines: Vec<_> = log.lines().collect();
+    let start = lines.len().saturating_sub(LOG_TAIL_LINES);
+    let tail = lines[start..].join("\n");
+    if start > 0 {
+        truncate_start(&format!("…\n{tail}"), LOG_TAIL_CHARS)
+    } else {
+        truncate_start(&tail, LOG_TAIL_CHARS)
+    }
+}
+
+/// Keeps the last `max` characters of the given string.
+fn truncate_start(s: &str, max: usize) -> String {
+    let len = s.chars().count();
+    if len <= max {
+        return s.to_string();
+    }
+    let tail: String = s.chars().skip(len - max + 1).collect();
+    format!("…{tail}")
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, m, s) => format!("{h}h {m}m {s}s"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::Signature;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    /// A request received by the stand-in server.
+    #[derive(Debug)]
+    struct Received {
+        method: String,
+        path: String,
+        headers: warp::http::HeaderMap,
+        body: Value,
+    }
+
+    /// Starts a local HTTP server standing in for the chat service which
+    /// passes on the requests it receives.
+    fn stand_in() -> (String, mpsc::UnboundedReceiver<Received>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let route = warp::method()
+            .and(warp::path::full())
+            .and(warp::header::headers_cloned())
+            .and(warp::body::json())
+            .map(move |method, path: warp::path::FullPath, headers, body| {
+                tx.send(Received {
+                    method: format!("{method}"),
+                    path: path.as_str().to_string(),
+                    headers,
+                    body,
+                })
+                .unwrap();
+                warp::reply()
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{addr}"), rx)
+    }
+
+    fn notification<'a>(
+        state: JobState,
+        reference: &'a Ref,
+        commit: Option<&'a CommitInfo>,
+        context: Option<&'a str>,
+    ) -> Notification<'a> {
+        Notification {
+            state,
+            project: "app",
+            job: "deploy",
+            reference,
+            commit,
+            duration: Some(Duration::from_secs(3)),
+            context,
+            url: Some("https://ci.example.com/api/runs/1".into()),
+        }
+    }
+
+    fn commit() -> CommitInfo {
+        let signature = Signature {
+            name: "Alice".into(),
+            email: "alice@example.com".into(),
+        };
+        CommitInfo {
+            sha: "0123456789abcdef".into(),
+            short_sha: "0123456".into(),
+            author: signature.clone(),
+            committer: signature,
+            timestamp: 0,
+            subject: "Fix <b>".into(),
+            body: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn slack() {
+        let (url, mut rx) = stand_in();
+        let url = format!("{url}/webhook");
+        let commit = commit();
+        let log: Vec<_> = (1..=30).map(|i| format!("line {i}")).collect();
+        let log = log.join("\n");
+
+        send_slack(
+            &url,
+            &Notification {
+                state: JobState::Failure,
+                project: "app",
+                job: "test",
+                reference: &Ref::Branch("main".into()),
+                commit: Some(&commit),
+                duration: Some(Duration::from_secs(65)),
+                context: Some(&log),
+                url: Some("https://ci.example.com/api/runs/1".into()),
+            },
+        )
+        .await
+        .unwrap();
+
+        let body = rx.recv().await.unwrap().body;
+        let attachment = &body["attachments"][0];
+        assert_eq!("Job failed: app / test", body["text"]);
+        assert_eq!("#e01e5a", attachment["color"]);
+        assert_eq!(
+            "https://ci.example.com/api/runs/1",
+            attachment["title_link"]
+        );
+        assert_eq!(
+            json!([
+                {"title": "Project", "value": "app", "short": true},
+                {"title": "Reference", "value": "refs/heads/main", "short": true},
+                {"title": "Commit", "value": "0123456 Fix &lt;b&gt;", "short": false},
+                {"title": "Duration", "value": "1m 5s", "short": true},
+            ]),
+            attachment["fields"]
+        );
+        let text = attachment["text"].as_str().unwrap();
+        assert!(text.starts_with("```\n…\nline 11\n"));
+        assert!(text.ends_with("line 30\n```"));
+    }
+
+    #[tokio::test]
+    async fn discord() {
+        let (url, mut rx) = stand_in();
+        let url = format!("{url}/webhook");
+
+        send_discord(
+            &url,
+            &Notification {
+                state: JobState::Success,
+                project: "app",
+                job: "deploy",
+                reference: &Ref::Tag("v1.0.0".into()),
+                commit: None,
+                duration: None,
+                context: Some(""),
+                url: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let body = rx.recv().await.unwrap().body;
+        let embed = &body["embeds"][0];
+        assert_eq!("Job finished successful: app / deploy", embed["title"]);
+        assert_eq!(0x2eb67d, embed["color"]);
+        assert_eq!(2, embed["fields"].as_array().unwrap().len());
+        assert!(embed.get("url").is_none());
+        assert!(embed.get("description").is_none());
+    }
+
+    #[tokio::test]
+    async fn matrix() {
+        let (url, mut rx) = stand_in();
+        let reference = Ref::Branch("main".into());
+        let commit = commit();
+
+        let n = notification(JobState::Failure, &reference, Some(&commit), Some("boom"));
+        send_matrix(&format!("{url}/"), "!room:example.com", "secret", &n)
+            .await
+            .unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!("PUT", req.method);
+        assert!(req
+            .path
+            .starts_with("/_matrix/client/v3/rooms/!room:example.com/send/m.room.message/minicd-"));
+        assert_eq!("Bearer secret", req.headers["authorization"]);
+        assert_eq!("m.text", req.body["msgtype"]);
+        assert_eq!("org.matrix.custom.html", req.body["format"]);
+        let html = req.body["formatted_body"].as_str().unwrap();
+        assert!(html.starts_with("<b>Job failed: app / deploy</b><br>Project: app<br>"));
+        assert!(html.contains("Commit: 0123456 Fix &lt;b&gt;"));
+        assert!(html.contains("<pre>boom</pre>"));
+
+        let n = notification(JobState::Success, &reference, None, None);
+        send_matrix(&url, "!room:example.com", "secret", &n)
+            .await
+            .unwrap();
+        let first = req.path;
+        let req = rx.recv().await.unwrap();
+        assert_eq!("m.notice", req.body["msgtype"]);
+        assert_ne!(first, req.path);
+    }
+
+    #[tokio::test]
+    async fn telegram() {
+        let (url, mut rx) = stand_in();
+        let reference = Ref::Tag("v1".into());
+
+        let n = notification(JobState::Start, &reference, None, None);
+        send_telegram(Some(&url), "123:abc", "-100", &n)
+            .await
+            .unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!("POST", req.method);
+        assert_eq!("/bot123:abc/sendMessage", req.path);
+        assert_eq!("-100", req.body["chat_id"]);
+        assert_eq!("HTML", req.body["parse_mode"]);
+        assert_eq!(true, req.body["disable_notification"]);
+        assert_eq!(
+            "<b>Job processing started: app / deploy</b>\n\
+            Project: app\n\
+            Reference: refs/tags/v1\n\
+            Duration: 3s\n\
+            <a href=\"https://ci.example.com/api/runs/1\">View run</a>",
+            req.body["text"]
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_without_url() {
+        let reference = Ref::Branch("main".into());
+        let n = notification(JobState::Failure, &reference, None, None);
+
+        let err = send_telegram(Some("http://127.0.0.1:1"), "123:secret", "-100", &n)
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("secret"));
+
+        let err = send_slack("http://127.0.0.1:1/services/T0/B0/secret", &n)
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn ntfy() {
+        let (url, mut rx) = stand_in();
+        let reference = Ref::Branch("main".into());
+
+        let n = notification(JobState::Failure, &reference, None, Some("error"));
+        send_ntfy(Some(&url), "builds", Some("tk_secret"), &n)
+            .await
+            .unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!("POST", req.method);
+        assert_eq!("Bearer tk_secret", req.headers["authorization"]);
+        assert_eq!("builds", req.body["topic"]);
+        assert_eq!("Job failed: app / deploy", req.body["title"]);
+        assert_eq!(
+            "Project: app\nReference: refs/heads/main\nDuration: 3s\n\nerror",
+            req.body["message"]
+        );
+        assert_eq!(4, req.body["priority"]);
+        assert_eq!("https://ci.example.com/api/runs/1", req.body["click"]);
+
+        let n = notification(JobState::Skipped, &reference, None, None);
+        send_ntfy(Some(&url), "builds", None, &n).await.unwrap();
+        let req = rx.recv().await.unwrap();
+        assert_eq!(2, req.body["priority"]);
+        assert!(req.headers.get("authorization").is_none());
+    }
+
+    #[tokio::test]
+    async fn gotify() {
+        let (url, mut rx) = stand_in();
+        let reference = Ref::Branch("main".into());
+
+        for (state, priority) in [
+            (JobState::Waiting, 8),
+            (JobState::Success, 5),
+            (JobState::Start, 2),
+        ] {
+            let n = notification(state, &reference, None, None);
+            send_gotify(&format!("{url}/"), "app-token", &n)
+                .await
+                .unwrap();
+
+            let req = rx.recv().await.unwrap();
+            assert_eq!("/message", req.path);
+            assert_eq!("app-token", req.headers["x-gotify-key"]);
+            assert_eq!(priority, req.body["priority"]);
+            assert_eq!(
+                "https://ci.example.com/api/runs/1",
+                req.body["extras"]["client::notification"]["click"]["url"]
+            );
+        }
+    }
+
+    #[test]
+    fn log_tail_limits() {
+        let log = "x".repeat(3000);
+        let tail = log_tail(&log);
+        assert_eq!(LOG_TAIL_CHARS, tail.chars().count());
+        assert!(tail.starts_with('…'));
+
+        assert_eq!("a\nb", log_tail("a\nb"));
+        assert_eq!("2h 0m 1s", format_duration(Duration::from_secs(7201)));
+    }
+}
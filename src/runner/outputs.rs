@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+/// Parses the outputs a job has written to the file passed via
+/// `MINICD_OUTPUT`. Each line is either a `key=value` pair or starts a
+/// multi-line value in the form `key<<DELIMITER`, which is terminated by
+/// a line only containing the delimiter.
+pub fn parse(content: &str) -> HashMap<String, String> {
+    let mut outputs = HashMap::new();
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        // A line only starts a multi-line value if the `<<` comes before any
+        // `=`, so values of pairs may contain `<<`.
+        let heredoc = line.split_once("<<").filter(|(key, _)| !key.contains('='));
+
+        if let Some((key, delimiter)) = heredoc {
+            let value: Vec<_> = lines
+                .by_ref()
+                .take_while(|l| l.trim_end() != delimiter.trim())
+                .collect();
+            insert(&mut outputs, key, value.join("\n"));
+        } else if let Some((key, value)) = line.split_once('=') {
+            insert(&mut outputs, key, value.to_string());
+        }
+    }
+
+    outputs
+}
+
+fn insert(outputs: &mut HashMap<String, String>, key: &str, value: String) {
+    let key = key.trim();
+    if !key.is_empty() {
+        outputs.insert(key.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let outputs = parse(
+            "tag=v1.2.3\nempty=\n = ignored\nurl=http://a?b=c\ninvalid line\ncmd=echo a<<b\n",
+        );
+
+        assert_eq!(
+            HashMap::from([
+                ("tag".to_string(), "v1.2.3".to_string()),
+                ("empty".to_string(), "".to_string()),
+                ("url".to_string(), "http://a?b=c".to_string()),
+                ("cmd".to_string(), "echo a<<b".to_string()),
+            ]),
+            outputs
+        );
+    }
+
+    #[test]
+    fn parse_multiline() {
+        let outputs = parse("notes<<EOF\nfirst\nsecond=2\nEOF\ntag=v1\nopen<<END\nrest");
+
+        assert_eq!(
+            HashMap::from([
+                ("notes".to_string(), "first\nsecond=2".to_string()),
+                ("tag".to_string(), "v1".to_string()),
+                ("open".to_string(), "rest".to_string()),
+            ]),
+            outputs
+        );
+    }
+}
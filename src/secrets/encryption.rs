@@ -0,0 +1,108 @@
+use super::{
+    environment,
+    error::{Error, Result},
+};
+use age::{scrypt, secrecy::SecretString, x25519};
+use std::{env, fs, path::Path};
+
+/// The header every binary age file starts with.
+const AGE_HEADER: &[u8] = b"age-encryption.org/";
+/// The name of the systemd credential containing the identity.
+const CREDENTIAL_NAME: &str = "minicd-secrets-key";
+
+/// The key used to decrypt and encrypt the secrets file.
+pub enum Key {
+    Identity(x25519::Identity),
+    Passphrase(SecretString),
+}
+
+impl Key {
+    /// Loads the key from the first available source of the given identity
+    /// file, the `minicd-secrets-key` systemd credential, the
+    /// `MINICD_SECRETS_KEY` environment variable containing an identity or
+    /// the `MINICD_SECRETS_PASSPHRASE` environment variable. The variables
+    /// are read from the environment minicd has been started with, as they
+    /// are removed from the environment passed to jobs.
+    pub fn load(key_file: Option<&Path>) -> Result<Option<Self>> {
+        if let Some(key_file) = key_file {
+            return parse_identity(&fs::read_to_string(key_file)?).map(Some);
+        }
+
+        if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") {
+            let path = Path::new(&dir).join(CREDENTIAL_NAME);
+            if path.exists() {
+                return parse_identity(&fs::read_to_string(path)?).map(Some);
+            }
+        }
+
+        if let Some(identity) = environment::var("MINICD_SECRETS_KEY") {
+            return parse_identity(&identity).map(Some);
+        }
+
+        if let Some(passphrase) = environment::var("MINICD_SECRETS_PASSPHRASE") {
+            return Ok(Some(Self::Passphrase(passphrase.into())));
+        }
+
+        Ok(None)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let data = match self {
+            Self::Identity(identity) => age::decrypt(identity, data)?,
+            Self::Passphrase(passphrase) => {
+                age::decrypt(&scrypt::Identity::new(passphrase.clone()), data)?
+            }
+        };
+        Ok(data)
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let data = match self {
+            Self::Identity(identity) => age::encrypt(&identity.to_public(), data)?,
+            Self::Passphrase(passphrase) => {
+                age::encrypt(&scrypt::Recipient::new(passphrase.clone()), data)?
+            }
+        };
+        Ok(data)
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AGE_HEADER)
+}
+
+/// Parses the first identity of the given age identity file content.
+fn parse_identity(content: &str) -> Result<Key> {
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .ok_or_else(|| Error::InvalidKey("no identity found".into()))?
+        .parse()
+        .map(Key::Identity)
+        .map_err(|err: &str| Error::InvalidKey(err.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    #[test]
+    fn round_trip() {
+        let identity = x25519::Identity::generate();
+        let key = parse_identity(&format!(
+            "# created: today\n{}\n",
+            identity.to_string().expose_secret()
+        ))
+        .unwrap();
+
+        let encrypted = key.encrypt(b"a: b").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(b"a: b"));
+        assert_eq!(b"a: b".to_vec(), key.decrypt(&encrypted).unwrap());
+
+        let other = Key::Identity(x25519::Identity::generate());
+        assert!(matches!(other.decrypt(&encrypted), Err(Error::Decrypt(_))));
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::HashMap, env, sync::OnceLock};
+
+/// Environment variables containing keys or tokens for the secrets, which
+/// must never be passed on to jobs.
+const PRIVATE_VARS: [&str; 3] = [
+    "MINICD_SECRETS_KEY",
+    "MINICD_SECRETS_PASSPHRASE",
+    "VAULT_TOKEN",
+];
+
+static SNAPSHOT: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// The environment of minicd as it was before private variables have been
+/// removed by [`scrub`], so that they are still available on reload.
+fn snapshot() -> &'static HashMap<String, String> {
+    SNAPSHOT.get_or_init(|| env::vars().collect())
+}
+
+/// Returns the value of the given variable from the environment minicd has
+/// been started with.
+pub fn var(key: &str) -> Option<String> {
+    snapshot().get(key).cloned()
+}
+
+/// Returns all variables of the environment minicd has been started with.
+pub fn vars() -> impl Iterator<Item = (String, String)> {
+    snapshot().iter().map(|(k, v)| (k.clone(), v.clone()))
+}
+
+/// Removes private variables and variables starting with one of the given
+/// prefixes of the env secret providers from the environment of the
+/// process. Jobs inherit the environment of minicd, so they would be
+/// readable by any job otherwise. As modifying the environment is not
+/// thread-safe, this must be called once before any threads are started.
+pub fn scrub(prefixes: &[&str]) {
+    for key in snapshot().keys().filter(|k| is_private(k, prefixes)) {
+        env::remove_var(key);
+    }
+}
+
+fn is_private(key: &str, prefixes: &[&str]) -> bool {
+    PRIVATE_VARS.contains(&key) || prefixes.iter().any(|p| key.starts_with(p))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn private_vars() {
+        let prefixes = ["MINICD_SECRET_", "CI_"];
+        assert!(is_private("MINICD_SECRETS_KEY", &[]));
+        assert!(is_private("MINICD_SECRETS_PASSPHRASE", &[]));
+        assert!(is_private("VAULT_TOKEN", &[]));
+        assert!(is_private("MINICD_SECRET_DOCKER__USERNAME", &prefixes));
+        assert!(is_private("CI_TOKEN", &prefixes));
+        assert!(!is_private("MINICD_SECRET_DOCKER__USERNAME", &[]));
+        assert!(!is_private("MINICD_SECRETS_KEY_FILE", &prefixes));
+        assert!(!is_private("PATH", &prefixes));
+    }
+}
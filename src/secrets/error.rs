@@ -0,0 +1,40 @@
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed accessing secrets file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("failed parsing secrets: {0}")]
+    Parse(#[from] serde_yaml::Error),
+
+    #[error("failed decrypting secrets file: {0}")]
+    Decrypt(#[from] age::DecryptError),
+
+    #[error("failed encrypting secrets file: {0}")]
+    Encrypt(#[from] age::EncryptError),
+
+    #[error("invalid secrets key: {0}")]
+    InvalidKey(String),
+
+    #[error("the secrets file is encrypted but no key has been provided")]
+    MissingKey,
+
+    #[error("invalid secret {key}: {message}")]
+    InvalidSecret { key: String, message: String },
+
+    #[error("secret {0} is not valid UTF-8")]
+    InvalidValue(String),
+
+    #[error("failed reading secrets from vault: {0}")]
+    Vault(#[from] reqwest::Error),
+
+    #[error("invalid response from vault: {0}")]
+    InvalidVaultResponse(String),
+
+    #[error("{0} is not a section")]
+    NotASection(String),
+
+    #[error("placeholder {0} can not be resolved")]
+    UnresolvedPlaceholder(String),
+}
@@ -0,0 +1,90 @@
+use super::error::Result;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use temp_dir::TempDir;
+
+/// Secrets written to private files outside of the checkout. The files are
+/// overwritten and removed when this is dropped.
+pub struct SecretFiles {
+    dir: TempDir,
+    files: Vec<PathBuf>,
+}
+
+impl SecretFiles {
+    pub fn new() -> Result<Self> {
+        let dir = TempDir::with_prefix("minicd-secrets-")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(Self { dir, files: vec![] })
+    }
+
+    /// Writes the secret with the given key to a file only readable by the
+    /// current user and returns its path.
+    pub fn write(&mut self, key: &str, value: &str) -> Result<PathBuf> {
+        let path = self.dir.path().join(key.replace(['/', '\\'], "_"));
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&path)?;
+        self.files.push(path.clone());
+        file.write_all(value.as_bytes())?;
+        file.sync_all()?;
+
+        Ok(path)
+    }
+}
+
+impl Drop for SecretFiles {
+    fn drop(&mut self) {
+        for file in &self.files {
+            if let Err(err) = shred(file) {
+                log::error!("Failed removing secret file {}: {err}", file.display());
+            }
+        }
+    }
+}
+
+/// Overwrites the file with zeros before removing it.
+fn shred(path: &Path) -> io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    io::copy(&mut io::repeat(0).take(len), &mut file)?;
+    file.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_and_remove() {
+        let mut files = SecretFiles::new().unwrap();
+        let path = files.write("deploy.key", "secret").unwrap();
+        let dir = path.parent().unwrap().to_path_buf();
+
+        assert_eq!("secret", fs::read_to_string(&path).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+
+        drop(files);
+        assert!(!path.exists());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,951 @@
+pub mod encryption;
+pub mod environment;
+pub mod error;
+pub mod files;
+pub mod providers;
+
+use crate::config::SecretPolicy;
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD},
+    Engine,
+};
+use encryption::Key;
+use error::{Error, Result};
+use glob::Pattern;
+use providers::SecretProvider;
+use serde::{de, Deserialize, Deserializer};
+use std::{collections::HashMap, fs, io::Write, path::Path};
+
+/// Secrets shorter than this are not masked, as they would most likely
+/// match unrelated content.
+const MIN_MASK_LEN: usize = 3;
+
+/// The section containing the secrets of single repositories.
+const REPOS_SECTION: &str = "repos";
+
+/// A secret or a section of secrets. Lists are accessible both as a whole,
+/// encoded as JSON, and by the index of their items like `hosts.0`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    /// A string tagged with `!file`, which is passed to jobs as file.
+    File(String),
+    Number(serde_json::Number),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_yaml::Value::deserialize(deserializer)? {
+            // An empty file does not contain any secrets.
+            serde_yaml::Value::Null => Ok(Value::Map(HashMap::new())),
+            value => Value::parse("", value).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl Value {
+    /// Converts a parsed YAML value, where `key` is the dotted key of the
+    /// value used in error messages.
+    fn parse(key: &str, value: serde_yaml::Value) -> Result<Self> {
+        let invalid = |message: &str| Error::InvalidSecret {
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+
+        match value {
+            serde_yaml::Value::Null => Err(invalid("the secret has no value")),
+            serde_yaml::Value::Bool(b) => Ok(Value::Bool(b)),
+            serde_yaml::Value::Number(n) => {
+                let number = if let Some(n) = n.as_u64() {
+                    Some(n.into())
+                } else if let Some(n) = n.as_i64() {
+                    Some(n.into())
+                } else {
+                    n.as_f64().and_then(serde_json::Number::from_f64)
+                };
+                number
+                    .map(Value::Number)
+                    .ok_or_else(|| invalid("numbers must be finite"))
+            }
+            serde_yaml::Value::String(v) => Ok(Value::String(v)),
+            serde_yaml::Value::Sequence(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| Value::parse(&join_key(key, &i.to_string()), item))
+                .collect::<Result<_>>()
+                .map(Value::List),
+            serde_yaml::Value::Mapping(map) => map
+                .into_iter()
+                .map(|(k, v)| {
+                    let k = match k {
+                        serde_yaml::Value::String(k) => k,
+                        serde_yaml::Value::Number(k) => k.to_string(),
+                        serde_yaml::Value::Bool(k) => k.to_string(),
+                        _ => return Err(invalid("keys must be strings")),
+                    };
+                    let v = Value::parse(&join_key(key, &k), v)?;
+                    Ok((k, v))
+                })
+                .collect::<Result<_>>()
+                .map(Value::Map),
+            serde_yaml::Value::Tagged(tagged) if tagged.tag == "file" => match tagged.value {
+                serde_yaml::Value::String(v) => Ok(Value::File(v)),
+                _ => Err(invalid("files must be strings")),
+            },
+            serde_yaml::Value::Tagged(tagged) => Err(invalid(&format!(
+                "tags like {} are not supported",
+                tagged.tag
+            ))),
+        }
+    }
+
+    /// Returns the value as string, which for lists is their JSON
+    /// representation. Sections have no value.
+    fn unwrap(&self) -> Option<String> {
+        match self {
+            Self::String(v) | Self::File(v) => Some(v.clone()),
+            Self::Number(n) => Some(n.to_string()),
+            Self::Bool(b) => Some(b.to_string()),
+            Self::List(_) => Some(self.to_json().to_string()),
+            Self::Map(_) => None,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::String(v) | Self::File(v) => serde_json::Value::String(v.clone()),
+            Self::Number(n) => serde_json::Value::Number(n.clone()),
+            Self::Bool(b) => serde_json::Value::Bool(*b),
+            Self::List(items) => items.iter().map(Value::to_json).collect(),
+            Self::Map(map) => map
+                .iter()
+                .map(|(k, v)| (k.clone(), v.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    /// Returns the child with the given key, which for lists is the index.
+    fn child(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Map(map) => map.get(key),
+            Self::List(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        }
+    }
+
+    /// Inserts the value at the given dotted key, creating missing sections
+    /// and replacing values which are in the way.
+    fn insert(&mut self, key: &str, value: Value) {
+        let mut target = self;
+        for part in key.split('.') {
+            if !matches!(target, Value::Map(_)) {
+                *target = Value::Map(HashMap::new());
+            }
+            let Value::Map(map) = target else {
+                unreachable!();
+            };
+            target = map
+                .entry(part.to_string())
+                .or_insert_with(|| Value::Map(HashMap::new()));
+        }
+        *target = value;
+    }
+
+    /// Merges the given value into this one. Sections are merged
+    /// recursively, everything else is replaced by the given value.
+    fn merge(&mut self, other: Value) {
+        match (self, other) {
+            (Value::Map(map), Value::Map(other)) => {
+                for (key, value) in other {
+                    match map.get_mut(&key) {
+                        Some(existing) => existing.merge(value),
+                        None => {
+                            map.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (this, other) => *this = other,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SecretManager {
+    secrets: Value,
+}
+
+impl SecretManager {
+    /// Reads the secrets from the given file, which is decrypted with the
+    /// given key if it is encrypted.
+    pub fn new(path: impl AsRef<Path>, key: Option<&Key>) -> Result<Self> {
+        let data = read_file(path, key)?;
+        let secrets = serde_yaml::from_slice(&data)?;
+        Ok(Self { secrets })
+    }
+
+    /// Loads the secrets from all given providers. Secrets of later
+    /// providers take precedence over the ones of earlier providers.
+    pub async fn load(providers: &[Box<dyn SecretProvider>]) -> Result<Self> {
+        let mut manager = Self::empty();
+        for provider in providers {
+            manager.secrets.merge(provider.load().await?);
+        }
+        Ok(manager)
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            secrets: Value::Map(HashMap::with_capacity(0)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.get_value(key).and_then(|v| v.unwrap())
+    }
+
+    /// Returns whether the secret with the given key is tagged with `!file`
+    /// and thus must be passed to jobs as file.
+    pub fn is_file(&self, key: &str) -> bool {
+        matches!(self.get_value(key), Some(Value::File(_)))
+    }
+
+    fn get_value(&self, key: &str) -> Option<&Value> {
+        let mut res = Some(&self.secrets);
+
+        for key in key.split('.') {
+            let Some(c) = res else {
+                break;
+            };
+            res = c.child(key);
+        }
+
+        res
+    }
+
+    /// Returns a manager only containing the given keys. A key may either
+    /// refer to a single secret or to a whole subtree like `docker`.
+    pub fn filter<S: AsRef<str>>(&self, keys: &[S]) -> Self {
+        let mut secrets = Value::Map(HashMap::new());
+
+        for key in keys {
+            let key = key.as_ref();
+            if let Some(value) = self.get_value(key) {
+                secrets.insert(key, value.clone());
+            }
+        }
+
+        Self { secrets }
+    }
+
+    /// Returns the secrets visible to the repository with the given remote.
+    /// Secrets in its `repos.<name>` section take precedence over the shared
+    /// secrets, while the secrets of other repositories are left out. See
+    /// [`repo_name`] for how the name is derived.
+    pub fn for_repo(&self, remote: &str, repo_dir: Option<&Path>) -> Self {
+        let mut secrets = self.secrets.clone();
+
+        let repo = match &mut secrets {
+            Value::Map(map) => match map.remove(REPOS_SECTION) {
+                Some(Value::Map(mut repos)) => repos.remove(&repo_name(remote, repo_dir)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(repo @ Value::Map(_)) = repo {
+            secrets.merge(repo);
+        }
+
+        Self { secrets }
+    }
+
+    /// Returns a manager only containing the secrets which the given policies
+    /// grant to the repository and reference. If there are no policies, all
+    /// secrets are accessible.
+    pub fn scoped(&self, policies: &[SecretPolicy], remote: &str, reference: &str) -> Self {
+        if policies.is_empty() {
+            return self.clone();
+        }
+
+        let matches = |patterns: &[String], value: &str| {
+            patterns
+                .iter()
+                .any(|p| Pattern::new(p).is_ok_and(|p| p.matches(value)))
+        };
+
+        let keys: Vec<_> = policies
+            .iter()
+            .filter(|p| matches(&p.repos, remote))
+            .filter(|p| p.refs.as_ref().is_none_or(|refs| matches(refs, reference)))
+            .flat_map(|p| &p.secrets)
+            .collect();
+
+        self.filter(&keys)
+    }
+
+    /// Replaces all `{{ key }}` placeholders in the given content. Keys are
+    /// resolved from `vars` first and then looked up in the secrets.
+    /// Placeholders which can not be resolved are left untouched.
+    pub fn replace(&self, content: &str, vars: &HashMap<String, String>) -> String {
+        self.render(content, vars, false)
+            .expect("lenient replacement never fails")
+    }
+
+    /// Like [`replace`](Self::replace), but fails on the first placeholder
+    /// which can not be resolved.
+    pub fn replace_strict(&self, content: &str, vars: &HashMap<String, String>) -> Result<String> {
+        self.render(content, vars, true)
+    }
+
+    fn render(
+        &self,
+        content: &str,
+        vars: &HashMap<String, String>,
+        strict: bool,
+    ) -> Result<String> {
+        let mut v = content;
+        let mut result = String::new();
+
+        while let Some(start) = v.find("{{") {
+            // Escaped braces are kept as literal braces.
+            if v[..start].ends_with('\\') {
+                result.push_str(&v[..start - 1]);
+                result.push_str("{{");
+                v = &v[start + 2..];
+                continue;
+            }
+
+            let next = &v[start + 2..];
+            let Some(end) = next.find("}}") else {
+                break;
+            };
+
+            result.push_str(&v[..start]);
+
+            let expr = next[..end].trim();
+
+            match self.resolve(expr, vars) {
+                Some(val) => {
+                    result.push_str(&val);
+                }
+                None if strict => {
+                    return Err(Error::UnresolvedPlaceholder(expr.to_string()));
+                }
+                None => {
+                    result.push_str(&v[start..start + 4 + end]);
+                }
+            }
+
+            v = &v[start + 4 + end..];
+        }
+
+        result.push_str(v);
+
+        Ok(result)
+    }
+
+    /// Resolves a placeholder expression like `key` or
+    /// `key | default("value")`.
+    fn resolve(&self, expr: &str, vars: &HashMap<String, String>) -> Option<String> {
+        let (key, filter) = match expr.split_once('|') {
+            Some((key, filter)) => (key.trim(), Some(filter.trim())),
+            None => (expr, None),
+        };
+
+        let value = vars.get(key).cloned().or_else(|| self.get(key));
+
+        match filter {
+            None => value,
+            Some(filter) => {
+                let default = parse_default(filter)?;
+                Some(value.unwrap_or(default))
+            }
+        }
+    }
+
+    /// Replaces all secret values and their base64 encodings in the given
+    /// content with `***`.
+    pub fn mask(&self, content: &str) -> String {
+        let mut values = vec![];
+        add_mask_values(&self.secrets, &mut values);
+        let mut values: Vec<_> = values
+            .into_iter()
+            .filter(|v| v.len() >= MIN_MASK_LEN)
+            .flat_map(|v| {
+                let mut encoded: Vec<_> = [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+                    .iter()
+                    .map(|engine| engine.encode(&v))
+                    .collect();
+                encoded.push(v);
+                encoded
+            })
+            .collect();
+
+        // Longer values are replaced first so that secrets containing other
+        // secrets are masked as a whole.
+        values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        values.dedup();
+
+        values.iter().fold(content.to_string(), |content, value| {
+            content.replace(value, "***")
+        })
+    }
+
+    pub fn to_flat_map(&self) -> HashMap<String, String> {
+        let mut hashmap = HashMap::new();
+        add_properties_to_hashmap("", &self.secrets, &mut hashmap);
+        hashmap
+    }
+}
+
+/// Returns the name of the repository with the given remote, which is its
+/// path relative to the repository directory or the full remote if it is
+/// not inside of it, each without the `.git` suffix.
+fn repo_name(remote: &str, repo_dir: Option<&Path>) -> String {
+    let remote = remote.trim_end_matches('/');
+    let name = repo_dir
+        .and_then(|dir| Path::new(remote).strip_prefix(dir).ok())
+        .and_then(|rel| rel.to_str())
+        .filter(|rel| !rel.is_empty())
+        .unwrap_or(remote);
+    name.strip_suffix(".git").unwrap_or(name).to_string()
+}
+
+/// Parses the argument of a `default("value")` filter, which may be quoted
+/// with either double or single quotes.
+fn parse_default(filter: &str) -> Option<String> {
+    let arg = filter
+        .strip_prefix("default")?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .trim();
+
+    ['"', '\'']
+        .iter()
+        .find_map(|q| arg.strip_prefix(*q)?.strip_suffix(*q))
+        .map(str::to_string)
+}
+
+/// Reads the given secrets file and decrypts it if it is encrypted.
+pub fn read_file(path: impl AsRef<Path>, key: Option<&Key>) -> Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    if !encryption::is_encrypted(&data) {
+        return Ok(data);
+    }
+    key.ok_or(Error::MissingKey)?.decrypt(&data)
+}
+
+/// Writes the given content to the secrets file, encrypting it if a key is
+/// given. The file is replaced atomically so that it is never left half
+/// written.
+pub fn write_file(path: impl AsRef<Path>, data: &[u8], key: Option<&Key>) -> Result<()> {
+    let path = path.as_ref();
+    let data = match key {
+        Some(key) => key.encrypt(data)?,
+        None => data.to_vec(),
+    };
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+fn add_properties_to_hashmap(
+    parent_key: &str,
+    value: &Value,
+    hashmap: &mut HashMap<String, String>,
+) {
+    if let Some(val) = value.unwrap() {
+        if !parent_key.is_empty() {
+            hashmap.insert(parent_key.to_string(), val);
+        }
+    }
+
+    match value {
+        Value::List(items) => {
+            for (i, value) in items.iter().enumerate() {
+                add_properties_to_hashmap(&join_key(parent_key, &i.to_string()), value, hashmap);
+            }
+        }
+        Value::Map(map) => {
+            for (key, value) in map.iter() {
+                add_properties_to_hashmap(&join_key(parent_key, key), value, hashmap);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the values which are masked in job output. Booleans are left
+/// out as masking every `true` and `false` would only obscure the output.
+fn add_mask_values(value: &Value, values: &mut Vec<String>) {
+    match value {
+        Value::String(v) | Value::File(v) => values.push(v.clone()),
+        Value::Number(n) => values.push(n.to_string()),
+        Value::Bool(_) => {}
+        Value::List(items) => items.iter().for_each(|v| add_mask_values(v, values)),
+        Value::Map(map) => map.values().for_each(|v| add_mask_values(v, values)),
+    }
+}
+
+fn join_key(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{parent}.{key}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "a".into(),
+            Value::Map(HashMap::from([("b".into(), Value::String("foo".into()))])),
+        )]));
+
+        assert_eq!(None, secrets.get("a"));
+        assert_eq!(None, secrets.get("a.c"));
+        assert_eq!(Some("foo".to_string()), secrets.get("a.b"));
+    }
+
+    #[test]
+    fn replace() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "a".into(),
+            Value::Map(HashMap::from([("b".into(), Value::String("foo".into()))])),
+        )]));
+
+        let vars = HashMap::new();
+
+        assert_eq!(
+            "hello foo this is foo world!",
+            secrets.replace("hello {{a.b}} this is {{a.b}} world!", &vars)
+        );
+
+        assert_eq!(
+            "hello foo this is {{a.c}} world!",
+            secrets.replace("hello {{a.b}} this is {{a.c}} world!", &vars)
+        );
+
+        assert_eq!(
+            "hello foo this is {{  a.c  }} world!",
+            secrets.replace("hello {{ a.b  }} this is {{  a.c  }} world!", &vars)
+        );
+
+        assert_eq!("foo {{ bar", secrets.replace("foo {{ bar", &vars));
+
+        assert_eq!(
+            "foo foo {{ bazz",
+            secrets.replace("foo {{ a.b }} {{ bazz", &vars)
+        );
+
+        assert_eq!(
+            "foo foo {{{{{}}foo",
+            secrets.replace("foo {{ a.b }} {{{{{}}{{ a.b }}", &vars)
+        );
+    }
+
+    #[test]
+    fn replace_default() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "a".into(),
+            Value::Map(HashMap::from([("b".into(), Value::String("foo".into()))])),
+        )]));
+
+        let vars = HashMap::new();
+
+        assert_eq!(
+            "foo bar baz",
+            secrets.replace(
+                r#"{{ a.b | default("x") }} {{ a.c | default("bar") }} {{a.c|default('baz')}}"#,
+                &vars
+            )
+        );
+        assert_eq!("", secrets.replace(r#"{{ a.c | default("") }}"#, &vars));
+        assert_eq!(
+            "{{ a.b | upper }} {{ a.c | default(bar) }}",
+            secrets.replace("{{ a.b | upper }} {{ a.c | default(bar) }}", &vars)
+        );
+    }
+
+    #[test]
+    fn replace_escaped() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "a".into(),
+            Value::Map(HashMap::from([("b".into(), Value::String("foo".into()))])),
+        )]));
+
+        let vars = HashMap::new();
+
+        assert_eq!(
+            "{{ a.b }} foo {{ .Values }}",
+            secrets.replace(r"\{{ a.b }} {{ a.b }} \{{ .Values }}", &vars)
+        );
+        assert_eq!(
+            "{{ a.b }}",
+            secrets.replace_strict(r"\{{ a.b }}", &vars).unwrap()
+        );
+    }
+
+    #[test]
+    fn replace_strict() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "a".into(),
+            Value::Map(HashMap::from([("b".into(), Value::String("foo".into()))])),
+        )]));
+
+        let vars = HashMap::new();
+
+        assert_eq!(
+            "foo bar",
+            secrets
+                .replace_strict(r#"{{ a.b }} {{ a.c | default("bar") }}"#, &vars)
+                .unwrap()
+        );
+        assert!(matches!(
+            secrets.replace_strict("{{ a.b }} {{ a.c }} {{ a.d }}", &vars),
+            Err(Error::UnresolvedPlaceholder(key)) if key == "a.c"
+        ));
+        assert_eq!(
+            "foo {{ bar",
+            secrets.replace_strict("{{ a.b }} {{ bar", &vars).unwrap()
+        );
+    }
+
+    #[test]
+    fn replace_vars() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "ref".into(),
+            Value::Map(HashMap::from([
+                ("branch".into(), Value::String("secret".into())),
+                ("other".into(), Value::String("bar".into())),
+            ])),
+        )]));
+
+        let vars = HashMap::from([("ref.branch".to_string(), "main".to_string())]);
+
+        assert_eq!(
+            "preview-main bar",
+            secrets.replace("preview-{{ ref.branch }} {{ref.other}}", &vars)
+        );
+    }
+
+    #[test]
+    fn filter() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([
+            (
+                "docker".into(),
+                Value::Map(HashMap::from([
+                    ("username".into(), Value::String("user".into())),
+                    ("password".into(), Value::String("pass".into())),
+                ])),
+            ),
+            ("token".into(), Value::String("token".into())),
+        ]));
+
+        let res = secrets.filter(&["docker.username", "missing", "token.nested"]);
+        assert_eq!(
+            HashMap::from([("docker.username".to_string(), "user".to_string())]),
+            res.to_flat_map()
+        );
+
+        let res = secrets.filter(&["docker"]);
+        assert_eq!(Some("pass".to_string()), res.get("docker.password"));
+        assert_eq!(None, res.get("token"));
+    }
+
+    #[test]
+    fn scoped() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([
+            ("prod".into(), Value::String("prod".into())),
+            ("hobby".into(), Value::String("hobby".into())),
+        ]));
+
+        let policies = vec![
+            SecretPolicy {
+                repos: vec!["/repos/*".into()],
+                refs: None,
+                secrets: vec!["hobby".into()],
+            },
+            SecretPolicy {
+                repos: vec!["/repos/app.git".into()],
+                refs: Some(vec!["refs/heads/main".into(), "refs/tags/*".into()]),
+                secrets: vec!["prod".into()],
+            },
+        ];
+
+        let keys = |remote, reference| {
+            let mut keys: Vec<_> = secrets
+                .scoped(&policies, remote, reference)
+                .to_flat_map()
+                .into_keys()
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(
+            vec!["hobby", "prod"],
+            keys("/repos/app.git", "refs/tags/v1")
+        );
+        assert_eq!(vec!["hobby"], keys("/repos/app.git", "refs/heads/dev"));
+        assert_eq!(vec!["hobby"], keys("/repos/other.git", "refs/heads/main"));
+        assert!(keys("/other/app.git", "refs/heads/main").is_empty());
+        assert_eq!(2, secrets.scoped(&[], "/other", "main").to_flat_map().len());
+    }
+
+    #[test]
+    fn for_repo() {
+        let secrets = SecretManager {
+            secrets: serde_yaml::from_str(
+                r#"
+docker:
+  username: shared
+  password: shared-password
+token: shared-token
+repos:
+  app:
+    docker:
+      password: app-password
+  other:
+    token: other-token
+  users/bob/app:
+    token: bob-token
+  "git@example.com:org/other":
+    token: remote-token
+"#,
+            )
+            .unwrap(),
+        };
+
+        let repo_dir = Some(Path::new("/home/git/repos"));
+        let app = secrets.for_repo("/home/git/repos/app.git", repo_dir);
+        assert_eq!(Some("shared".to_string()), app.get("docker.username"));
+        assert_eq!(Some("app-password".to_string()), app.get("docker.password"));
+        assert_eq!(Some("shared-token".to_string()), app.get("token"));
+        assert_eq!(None, app.get("repos.other.token"));
+
+        let other = secrets.for_repo("/home/git/repos/other.git/", repo_dir);
+        assert_eq!(Some("other-token".to_string()), other.get("token"));
+        assert_eq!(
+            Some("shared-password".to_string()),
+            other.get("docker.password")
+        );
+
+        // Repositories with the same name in different directories do not
+        // share their secrets.
+        let bob = secrets.for_repo("/home/git/repos/users/bob/app.git", repo_dir);
+        assert_eq!(Some("bob-token".to_string()), bob.get("token"));
+        assert_eq!(
+            Some("shared-password".to_string()),
+            bob.get("docker.password")
+        );
+        let outside = secrets.for_repo("/srv/git/app.git", repo_dir);
+        assert_eq!(Some("shared-token".to_string()), outside.get("token"));
+        assert_eq!(
+            Some("shared-password".to_string()),
+            outside.get("docker.password")
+        );
+
+        let remote = secrets.for_repo("git@example.com:org/other.git", repo_dir);
+        assert_eq!(Some("remote-token".to_string()), remote.get("token"));
+
+        let unknown = secrets.for_repo("/home/git/repos/unknown", repo_dir);
+        assert_eq!(3, unknown.to_flat_map().len());
+        let no_repo_dir = secrets.for_repo("/home/git/repos/app.git", None);
+        assert_eq!(3, no_repo_dir.to_flat_map().len());
+    }
+
+    #[test]
+    fn repo_name() {
+        let dir = Some(Path::new("/home/git/repos"));
+        assert_eq!("app", super::repo_name("/home/git/repos/app.git", dir));
+        assert_eq!("app", super::repo_name("/home/git/repos/app/", dir));
+        assert_eq!(
+            "users/bob/app",
+            super::repo_name("/home/git/repos/users/bob/app.git", dir)
+        );
+        assert_eq!(
+            "/home/git/repos-old/app",
+            super::repo_name("/home/git/repos-old/app.git", dir)
+        );
+        assert_eq!(
+            "/home/git/repos/app",
+            super::repo_name("/home/git/repos/app.git", None)
+        );
+        assert_eq!(
+            "git@example.com:app",
+            super::repo_name("git@example.com:app.git", dir)
+        );
+        assert_eq!(
+            "https://example.com/org/app",
+            super::repo_name("https://example.com/org/app.git", dir)
+        );
+    }
+
+    #[test]
+    fn mask() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([
+            ("password".into(), Value::String("hunter2".into())),
+            ("prefixed".into(), Value::String("hunter2-prod".into())),
+            ("short".into(), Value::String("ab".into())),
+        ]));
+
+        assert_eq!(
+            "pass: *** *** ab\nencoded: ***, ***",
+            secrets.mask("pass: hunter2 hunter2-prod ab\nencoded: aHVudGVyMg==, aHVudGVyMg")
+        );
+        assert_eq!("nothing", secrets.mask("nothing"));
+    }
+
+    #[test]
+    fn parse_types() {
+        let secrets = SecretManager {
+            secrets: serde_yaml::from_str(
+                r#"
+db:
+  port: 5432
+  ratio: 0.5
+  enabled: true
+  password: |
+    multi
+    line
+hosts:
+  - a.example.com
+  - b.example.com
+servers:
+  - name: a
+    port: 22
+"#,
+            )
+            .unwrap(),
+        };
+
+        assert_eq!(Some("5432".to_string()), secrets.get("db.port"));
+        assert_eq!(Some("0.5".to_string()), secrets.get("db.ratio"));
+        assert_eq!(Some("true".to_string()), secrets.get("db.enabled"));
+        assert_eq!(
+            Some("multi\nline\n".to_string()),
+            secrets.get("db.password")
+        );
+        assert_eq!(Some("b.example.com".to_string()), secrets.get("hosts.1"));
+        assert_eq!(None, secrets.get("hosts.2"));
+        assert_eq!(
+            Some(r#"["a.example.com","b.example.com"]"#.to_string()),
+            secrets.get("hosts")
+        );
+        assert_eq!(
+            Some(r#"[{"name":"a","port":22}]"#.to_string()),
+            secrets.get("servers")
+        );
+        assert_eq!(Some("22".to_string()), secrets.get("servers.0.port"));
+
+        let flat = secrets.to_flat_map();
+        assert_eq!(Some(&"a.example.com".to_string()), flat.get("hosts.0"));
+        assert!(flat.contains_key("hosts"));
+        assert_eq!(Some(&"a".to_string()), flat.get("servers.0.name"));
+
+        let filtered = secrets.filter(&["hosts.0", "db.port"]).to_flat_map();
+        assert_eq!(2, filtered.len());
+        assert_eq!(Some(&"a.example.com".to_string()), filtered.get("hosts.0"));
+
+        let empty: Value = serde_yaml::from_str("").unwrap();
+        assert_eq!(Value::Map(HashMap::new()), empty);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |content| {
+            serde_yaml::from_str::<Value>(content)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(err("docker:\n  password:\n").contains("invalid secret docker.password"));
+        assert!(err("hosts:\n  - a\n  - ~\n").contains("invalid secret hosts.1"));
+        assert!(err("key: !secret foo\n").contains("invalid secret key"));
+        assert!(err("a:\n  [1, 2]: x\n").contains("invalid secret a: keys must be strings"));
+        assert!(err("key: !file [a]\n").contains("invalid secret key: files must be strings"));
+    }
+
+    #[test]
+    fn parse_files() {
+        let secrets = SecretManager {
+            secrets: serde_yaml::from_str("deploy:\n  key: !file |\n    private\n  user: deploy")
+                .unwrap(),
+        };
+
+        assert!(secrets.is_file("deploy.key"));
+        assert!(!secrets.is_file("deploy.user"));
+        assert!(!secrets.is_file("deploy"));
+        assert_eq!(Some("private\n".to_string()), secrets.get("deploy.key"));
+        assert_eq!("***", secrets.mask("private\n"));
+    }
+
+    #[test]
+    fn mask_types() {
+        let secrets = SecretManager {
+            secrets: serde_yaml::from_str("pin: 48151\nenabled: true\nhosts: [secret-host]")
+                .unwrap(),
+        };
+
+        assert_eq!(
+            "*** true *** [\"***\"]",
+            secrets.mask("48151 true secret-host [\"secret-host\"]")
+        );
+    }
+
+    #[test]
+    fn to_flat_map() {
+        let mut secrets = SecretManager::empty();
+        secrets.secrets = Value::Map(HashMap::from([(
+            "a".into(),
+            Value::Map(HashMap::from([
+                ("b".into(), Value::String("foo".into())),
+                ("c".into(), Value::String("bar".into())),
+            ])),
+        )]));
+
+        let res = secrets.to_flat_map();
+
+        assert_eq!(
+            HashMap::from([
+                ("a.b".to_string(), "foo".to_string()),
+                ("a.c".to_string(), "bar".to_string())
+            ]),
+            res
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use super::{
+    encryption::Key,
+    environment,
+    error::{Error, Result},
+    read_file, Value,
+};
+use crate::config::{Config, SecretProviderConfig};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// A source of secrets which are loaded into the [`SecretManager`](super::SecretManager).
+pub trait SecretProvider: Send + Sync {
+    fn load(&self) -> BoxFuture<'_, Result<Value>>;
+}
+
+/// Creates the providers configured in the given config. A configured
+/// `secrets_file` is always loaded first.
+pub fn from_config(cfg: &Config) -> Result<Vec<Box<dyn SecretProvider>>> {
+    let key = Key::load(cfg.secrets_key_file.as_deref())?.map(std::sync::Arc::new);
+
+    let mut providers: Vec<Box<dyn SecretProvider>> = vec![];
+    if let Some(path) = &cfg.secrets_file {
+        providers.push(Box::new(YamlProvider {
+            path: path.into(),
+            key: key.clone(),
+        }));
+    }
+
+    for provider in &cfg.secret_providers {
+        let provider: Box<dyn SecretProvider> = match provider {
+            SecretProviderConfig::Yaml { path } => Box::new(YamlProvider {
+                path: path.clone(),
+                key: key.clone(),
+            }),
+            SecretProviderConfig::Env { prefix } => Box::new(EnvProvider {
+                prefix: prefix.clone(),
+            }),
+            SecretProviderConfig::Directory { path } => Box::new(DirectoryProvider {
+                path: path
+                    .clone()
+                    .or_else(|| env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from)),
+            }),
+            SecretProviderConfig::Vault {
+                address,
+                token,
+                mount,
+                path,
+                kv_version,
+            } => Box::new(VaultProvider {
+                address: address.trim_end_matches('/').to_string(),
+                token: token.clone().or_else(|| environment::var("VAULT_TOKEN")),
+                mount: mount.clone().unwrap_or_else(|| "secret".into()),
+                path: path.trim_matches('/').to_string(),
+                kv_version: kv_version.unwrap_or(2),
+            }),
+        };
+        providers.push(provider);
+    }
+
+    Ok(providers)
+}
+
+/// Reads secrets from a YAML file, which may be encrypted.
+pub struct YamlProvider {
+    path: PathBuf,
+    key: Option<std::sync::Arc<Key>>,
+}
+
+impl SecretProvider for YamlProvider {
+    fn load(&self) -> BoxFuture<'_, Result<Value>> {
+        let res = read_file(&self.path, self.key.as_deref())
+            .and_then(|data| serde_yaml::from_slice(&data).map_err(Error::from));
+        future::ready(res).boxed()
+    }
+}
+
+/// Reads secrets from environment variables starting with the prefix. The
+/// remaining name is lowercased and sections are separated by `__`, so
+/// `<prefix>DOCKER__USERNAME` becomes `docker.username`. The variables are
+/// read from the environment minicd has been started with, as they are
+/// removed from the environment passed to jobs.
+pub struct EnvProvider {
+    prefix: String,
+}
+
+impl SecretProvider for EnvProvider {
+    fn load(&self) -> BoxFuture<'_, Result<Value>> {
+        future::ready(Ok(from_env(&self.prefix, environment::vars()))).boxed()
+    }
+}
+
+fn from_env(prefix: &str, vars: impl Iterator<Item = (String, String)>) -> Value {
+    let mut secrets = Value::Map(HashMap::new());
+    for (name, value) in vars {
+        let Some(name) = name.strip_prefix(prefix).filter(|n| !n.is_empty()) else {
+            continue;
+        };
+        secrets.insert(
+            &name.to_lowercase().replace("__", "."),
+            Value::String(value),
+        );
+    }
+    secrets
+}
+
+/// Reads secrets from a directory containing one file per secret, like
+/// Docker and Kubernetes secret mounts or systemd credentials. Dots in file
+/// names and subdirectories separate sections. Hidden files are ignored.
+pub struct DirectoryProvider {
+    path: Option<PathBuf>,
+}
+
+impl SecretProvider for DirectoryProvider {
+    fn load(&self) -> BoxFuture<'_, Result<Value>> {
+        let mut secrets = Value::Map(HashMap::new());
+        let res = match &self.path {
+            Some(path) => from_dir(path, "", &mut secrets).map(|_| secrets),
+            None => {
+                log::warn!("Secrets directory is not set and $CREDENTIALS_DIRECTORY is empty");
+                Ok(secrets)
+            }
+        };
+        future::ready(res).boxed()
+    }
+}
+
+fn from_dir(dir: &Path, prefix: &str, secrets: &mut Value) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let key = format!("{prefix}{name}");
+        let path = entry.path();
+        if path.is_dir() {
+            from_dir(&path, &format!("{key}."), secrets)?;
+        } else {
+            let value = String::from_utf8(fs::read(&path)?)
+                .map_err(|_| Error::InvalidValue(key.clone()))?;
+            let value = value.strip_suffix('\n').unwrap_or(&value);
+            secrets.insert(&key, Value::String(value.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Reads secrets from a HashiCorp Vault compatible KV secrets engine.
+pub struct VaultProvider {
+    address: String,
+    token: Option<String>,
+    mount: String,
+    path: String,
+    kv_version: u8,
+}
+
+impl SecretProvider for VaultProvider {
+    fn load(&self) -> BoxFuture<'_, Result<Value>> {
+        async move {
+            let url = match self.kv_version {
+                1 => format!("{}/v1/{}/{}", self.address, self.mount, self.path),
+                _ => format!("{}/v1/{}/data/{}", self.address, self.mount, self.path),
+            };
+
+            let mut req = reqwest::Client::default().get(url);
+            if let Some(token) = &self.token {
+                req = req.header("X-Vault-Token", token);
+            }
+            let body = req.send().await?.error_for_status()?.bytes().await?;
+
+            from_vault_response(&body, self.kv_version)
+        }
+        .boxed()
+    }
+}
+
+fn from_vault_response(body: &[u8], kv_version: u8) -> Result<Value> {
+    let response: serde_json::Value =
+        serde_json::from_slice(body).map_err(|err| Error::InvalidVaultResponse(err.to_string()))?;
+
+    let data = match kv_version {
+        1 => response.get("data"),
+        _ => response.get("data").and_then(|d| d.get("data")),
+    };
+    let Some(serde_json::Value::Object(data)) = data else {
+        return Err(Error::InvalidVaultResponse("missing secret data".into()));
+    };
+
+    let mut secrets = Value::Map(HashMap::new());
+    for (key, value) in data {
+        let value = serde_yaml::to_value(value)
+            .map_err(|err| Error::InvalidVaultResponse(err.to_string()))?;
+        secrets.insert(key, Value::parse(key, value)?);
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secrets::SecretManager;
+
+    #[test]
+    fn env() {
+        let secrets = SecretManager {
+            secrets: from_env(
+                "SECRET_",
+                [
+                    ("SECRET_DOCKER__USERNAME", "user"),
+                    ("SECRET_API_TOKEN", "token"),
+                    ("SECRET_", "ignored"),
+                    ("OTHER", "ignored"),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+            ),
+        };
+
+        assert_eq!(
+            HashMap::from([
+                ("docker.username".to_string(), "user".to_string()),
+                ("api_token".to_string(), "token".to_string()),
+            ]),
+            secrets.to_flat_map()
+        );
+    }
+
+    #[test]
+    fn directory() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        fs::write(dir.path().join("docker.username"), "user\n").unwrap();
+        fs::create_dir(dir.path().join("db")).unwrap();
+        fs::write(dir.path().join("db").join("password"), "pass").unwrap();
+        fs::create_dir(dir.path().join("..data")).unwrap();
+        fs::write(dir.path().join("..data").join("hidden"), "hidden").unwrap();
+
+        let mut secrets = Value::Map(HashMap::new());
+        from_dir(dir.path(), "", &mut secrets).unwrap();
+
+        assert_eq!(
+            HashMap::from([
+                ("docker.username".to_string(), "user".to_string()),
+                ("db.password".to_string(), "pass".to_string()),
+            ]),
+            SecretManager { secrets }.to_flat_map()
+        );
+    }
+
+    #[test]
+    fn vault_response() {
+        let body = br#"{"data": {"data": {"docker.username": "user", "db": {"port": 5432}}}}"#;
+        let secrets = SecretManager {
+            secrets: from_vault_response(body, 2).unwrap(),
+        };
+        assert_eq!(Some("user".to_string()), secrets.get("docker.username"));
+        assert_eq!(Some("5432".to_string()), secrets.get("db.port"));
+
+        assert!(from_vault_response(br#"{"data": {"a": "b"}}"#, 2).is_err());
+        assert!(from_vault_response(br#"{"data": {"a": "b"}}"#, 1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn layered() {
+        struct Static(&'static str);
+        impl SecretProvider for Static {
+            fn load(&self) -> BoxFuture<'_, Result<Value>> {
+                future::ready(serde_yaml::from_str(self.0).map_err(Error::from)).boxed()
+            }
+        }
+
+        let providers: Vec<Box<dyn SecretProvider>> = vec![
+            Box::new(Static("docker: {username: a, password: b}\ntoken: c")),
+            Box::new(Static("docker: {username: d}")),
+        ];
+        let secrets = SecretManager::load(&providers).await.unwrap();
+
+        assert_eq!(
+            HashMap::from([
+                ("docker.username".to_string(), "d".to_string()),
+                ("docker.password".to_string(), "b".to_string()),
+                ("token".to_string(), "c".to_string()),
+            ]),
+            secrets.to_flat_map()
+        );
+    }
+}
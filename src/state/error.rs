@@ -0,0 +1,10 @@
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed accessing state file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("failed (de)serializing state file: {0}")]
+    Serialization(#[from] serde_yaml::Error),
+}
@@ -0,0 +1,474 @@
+pub mod error;
+
+use error::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STATE_FILE_NAME: &str = "state.yaml";
+const MAX_DEPLOYMENTS_PER_ENVIRONMENT: usize = 100;
+const MAX_RUNS: usize = 1000;
+/// The delay (in seconds) before a failed stop of an environment is
+/// retried. It doubles with each failed attempt.
+const STOP_RETRY_DELAY_SECS: u64 = 600;
+/// The number of failed stop attempts after which an environment is no
+/// longer stopped automatically.
+pub const MAX_STOP_ATTEMPTS: u32 = 5;
+
+/// A live environment which has been deployed by a job.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Environment {
+    pub name: String,
+    pub remote: String,
+    pub reference: String,
+    pub commit: String,
+    pub job: String,
+    pub on_stop: Option<String>,
+    pub deployed_at: u64,
+    /// The number of failed attempts to stop the environment.
+    #[serde(default)]
+    pub stop_attempts: u32,
+    #[serde(default)]
+    pub stop_failed_at: Option<u64>,
+}
+
+impl Environment {
+    /// Whether an automatic stop of the environment may be attempted at the
+    /// given time. Failed attempts are retried with an increasing delay
+    /// until [`MAX_STOP_ATTEMPTS`] is reached.
+    pub fn stop_due(&self, now: u64) -> bool {
+        let Some(failed_at) = self.stop_failed_at else {
+            return true;
+        };
+        if self.stop_attempts >= MAX_STOP_ATTEMPTS {
+            return false;
+        }
+        let delay = STOP_RETRY_DELAY_SECS << self.stop_attempts.saturating_sub(1);
+        now >= failed_at.saturating_add(delay)
+    }
+}
+
+/// A successful run of a job deploying to an environment.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Deployment {
+    pub environment: String,
+    pub remote: String,
+    pub reference: String,
+    pub commit: String,
+    pub job: String,
+    pub deployed_at: u64,
+    pub triggered_by: String,
+}
+
+/// A single execution of a definition, triggered either by a push or via
+/// the API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Run {
+    pub id: u64,
+    pub name: String,
+    pub remote: String,
+    pub reference: String,
+    pub commit: String,
+    pub triggered_by: String,
+    pub started_at: u64,
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+}
+
+/// An archive of files collected from a job of a run.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Artifact {
+    pub name: String,
+    pub size: u64,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct State {
+    #[serde(default)]
+    environments: Vec<Environment>,
+    #[serde(default)]
+    deployments: Vec<Deployment>,
+    #[serde(default)]
+    next_run_id: u64,
+    #[serde(default)]
+    runs: Vec<Run>,
+}
+
+/// Keeps track of the runner state. When a data directory is given, the
+/// state is persisted in there after each modification. Otherwise, the
+/// state is only held in memory.
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl StateStore {
+    pub fn open(data_dir: Option<impl AsRef<Path>>) -> Result<Self> {
+        let Some(data_dir) = data_dir else {
+            return Ok(Self::in_memory());
+        };
+
+        let data_dir = data_dir.as_ref();
+        fs::create_dir_all(data_dir)?;
+
+        let path = data_dir.join(STATE_FILE_NAME);
+        let state = if path.exists() {
+            serde_yaml::from_reader(File::open(&path)?)?
+        } else {
+            State::default()
+        };
+
+        Ok(Self {
+            path: Some(path),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn environments(&self) -> Vec<Environment> {
+        self.lock().environments.clone()
+    }
+
+    pub fn environments_for_ref(&self, remote: &str, reference: &str) -> Vec<Environment> {
+        self.lock()
+            .environments
+            .iter()
+            .filter(|env| env.remote == remote && env.reference == reference)
+            .cloned()
+            .collect()
+    }
+
+    /// Records a deployment to the given environment and marks the
+    /// environment as live with the deployed commit.
+    pub fn record_deployment(&self, env: Environment, triggered_by: &str) -> Result<()> {
+        self.update(|state| {
+            state.deployments.push(Deployment {
+                environment: env.name.clone(),
+                remote: env.remote.clone(),
+                reference: env.reference.clone(),
+                commit: env.commit.clone(),
+                job: env.job.clone(),
+                deployed_at: env.deployed_at,
+                triggered_by: triggered_by.to_string(),
+            });
+
+            let count = state
+                .deployments
+                .iter()
+                .filter(|d| d.remote == env.remote && d.environment == env.name)
+                .count();
+            if count > MAX_DEPLOYMENTS_PER_ENVIRONMENT {
+                if let Some(i) = state
+                    .deployments
+                    .iter()
+                    .position(|d| d.remote == env.remote && d.environment == env.name)
+                {
+                    state.deployments.remove(i);
+                }
+            }
+
+            state
+                .environments
+                .retain(|e| e.remote != env.remote || e.name != env.name);
+            state.environments.push(env);
+        })
+    }
+
+    /// Returns the deployments of the environment with the given name,
+    /// newest first. When no remote is given, deployments of environments
+    /// with that name of all remotes are returned.
+    pub fn deployments(&self, remote: Option<&str>, environment: &str) -> Vec<Deployment> {
+        self.lock()
+            .deployments
+            .iter()
+            .rev()
+            .filter(|d| d.environment == environment && remote.is_none_or(|r| d.remote == r))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the deployment a rollback of the given environment returns to,
+    /// which is the last deployment of a commit other than the current one.
+    pub fn rollback_target(&self, env: &Environment) -> Option<Deployment> {
+        self.deployments(Some(&env.remote), &env.name)
+            .into_iter()
+            .find(|d| d.commit != env.commit)
+    }
+
+    /// Records a new run and returns the ID assigned to it. The ID of the
+    /// given run is ignored.
+    pub fn create_run(&self, mut run: Run) -> Result<u64> {
+        self.update(|state| {
+            state.next_run_id += 1;
+            run.id = state.next_run_id;
+            state.runs.push(run);
+
+            if state.runs.len() > MAX_RUNS {
+                let excess = state.runs.len() - MAX_RUNS;
+                state.runs.drain(..excess);
+            }
+
+            state.next_run_id
+        })
+    }
+
+    pub fn runs(&self) -> Vec<Run> {
+        self.lock().runs.iter().rev().cloned().collect()
+    }
+
+    pub fn run(&self, id: u64) -> Option<Run> {
+        self.lock().runs.iter().find(|r| r.id == id).cloned()
+    }
+
+    pub fn add_artifact(&self, run_id: u64, artifact: Artifact) -> Result<()> {
+        self.update(|state| {
+            if let Some(run) = state.runs.iter_mut().find(|r| r.id == run_id) {
+                run.artifacts.retain(|a| a.name != artifact.name);
+                run.artifacts.push(artifact);
+            }
+        })
+    }
+
+    pub fn remove_artifacts(&self, run_ids: &[u64]) -> Result<()> {
+        self.update(|state| {
+            for run in state.runs.iter_mut().filter(|r| run_ids.contains(&r.id)) {
+                run.artifacts.clear();
+            }
+        })
+    }
+
+    /// Records a failed attempt to stop the environment and returns the
+    /// number of failed attempts so far.
+    pub fn record_stop_failure(&self, remote: &str, name: &str) -> Result<u32> {
+        self.update(|state| {
+            let Some(env) = state
+                .environments
+                .iter_mut()
+                .find(|e| e.remote == remote && e.name == name)
+            else {
+                return 0;
+            };
+            env.stop_attempts += 1;
+            env.stop_failed_at = Some(now());
+            env.stop_attempts
+        })
+    }
+
+    pub fn remove_environment(&self, remote: &str, name: &str) -> Result<()> {
+        self.update(|state| {
+            state
+                .environments
+                .retain(|e| e.remote != remote || e.name != name);
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut State) -> R) -> Result<R> {
+        let mut state = self.lock();
+
+        // The modification is applied to a copy, which only replaces the
+        // state in memory once it has been persisted. Otherwise, memory and
+        // disk would be out of sync after a failed write.
+        let mut updated = state.clone();
+        let res = f(&mut updated);
+
+        if let Some(path) = &self.path {
+            let tmp_path = path.with_extension("tmp");
+            serde_yaml::to_writer(File::create(&tmp_path)?, &updated)?;
+            fs::rename(tmp_path, path)?;
+        }
+
+        *state = updated;
+        Ok(res)
+    }
+}
+
+/// Returns the current time as UNIX timestamp in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn environment() -> Environment {
+        Environment {
+            name: "preview".into(),
+            remote: "/repos/app.git".into(),
+            reference: "refs/heads/feature".into(),
+            commit: "abc".into(),
+            job: "deploy".into(),
+            on_stop: Some("teardown".into()),
+            deployed_at: 0,
+            stop_attempts: 0,
+            stop_failed_at: None,
+        }
+    }
+
+    #[test]
+    fn stop_failures() {
+        let store = StateStore::in_memory();
+        store.record_deployment(environment(), "bob").unwrap();
+        assert!(store.environments()[0].stop_due(now()));
+
+        assert_eq!(
+            1,
+            store
+                .record_stop_failure("/repos/app.git", "preview")
+                .unwrap()
+        );
+        let env = store.environments().remove(0);
+        let failed_at = env.stop_failed_at.unwrap();
+        assert!(!env.stop_due(failed_at));
+        assert!(!env.stop_due(failed_at + STOP_RETRY_DELAY_SECS - 1));
+        assert!(env.stop_due(failed_at + STOP_RETRY_DELAY_SECS));
+
+        assert_eq!(
+            2,
+            store
+                .record_stop_failure("/repos/app.git", "preview")
+                .unwrap()
+        );
+        let env = store.environments().remove(0);
+        let failed_at = env.stop_failed_at.unwrap();
+        assert!(!env.stop_due(failed_at + STOP_RETRY_DELAY_SECS));
+        assert!(env.stop_due(failed_at + 2 * STOP_RETRY_DELAY_SECS));
+
+        for _ in 2..MAX_STOP_ATTEMPTS {
+            store
+                .record_stop_failure("/repos/app.git", "preview")
+                .unwrap();
+        }
+        let env = store.environments().remove(0);
+        assert_eq!(MAX_STOP_ATTEMPTS, env.stop_attempts);
+        assert!(!env.stop_due(u64::MAX));
+
+        // A new deployment resets the failed attempts.
+        store.record_deployment(environment(), "bob").unwrap();
+        assert!(store.environments()[0].stop_due(now()));
+
+        assert_eq!(
+            0,
+            store
+                .record_stop_failure("/repos/other.git", "preview")
+                .unwrap()
+        );
+    }
+
+    fn deploy(store: &StateStore, commit: &str, deployed_at: u64, triggered_by: &str) {
+        let env = Environment {
+            commit: commit.into(),
+            deployed_at,
+            ..environment()
+        };
+        store.record_deployment(env, triggered_by).unwrap();
+    }
+
+    #[test]
+    fn deployments() {
+        let store = StateStore::in_memory();
+        deploy(&store, "a", 1, "alice");
+        deploy(&store, "b", 2, "bob");
+        store
+            .record_deployment(
+                Environment {
+                    remote: "/repos/other.git".into(),
+                    ..environment()
+                },
+                "carol",
+            )
+            .unwrap();
+
+        let deployments = store.deployments(Some("/repos/app.git"), "preview");
+        assert_eq!(
+            vec![("b", "bob"), ("a", "alice")],
+            deployments
+                .iter()
+                .map(|d| (d.commit.as_str(), d.triggered_by.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(3, store.deployments(None, "preview").len());
+        assert!(store.deployments(None, "production").is_empty());
+
+        // The live environment is replaced by the latest deployment.
+        let envs = store.environments_for_ref("/repos/app.git", "refs/heads/feature");
+        assert_eq!(1, envs.len());
+        assert_eq!("b", envs[0].commit);
+
+        for i in 0..MAX_DEPLOYMENTS_PER_ENVIRONMENT as u64 {
+            deploy(&store, "c", 3 + i, "alice");
+        }
+        let deployments = store.deployments(Some("/repos/app.git"), "preview");
+        assert_eq!(MAX_DEPLOYMENTS_PER_ENVIRONMENT, deployments.len());
+        assert!(deployments.iter().all(|d| d.commit == "c"));
+    }
+
+    #[test]
+    fn rollback_target() {
+        let store = StateStore::in_memory();
+        deploy(&store, "a", 1, "alice");
+        let current = store.environments().remove(0);
+        assert!(store.rollback_target(&current).is_none());
+
+        // Redeployments of the current commit are skipped.
+        deploy(&store, "b", 2, "bob");
+        deploy(&store, "c", 3, "bob");
+        deploy(&store, "c", 4, "bob");
+        let current = store.environments().remove(0);
+        let target = store.rollback_target(&current).unwrap();
+        assert_eq!(("b", 2), (target.commit.as_str(), target.deployed_at));
+
+        // After rolling back, the target is the commit deployed before.
+        deploy(&store, "b", 5, "alice");
+        let current = store.environments().remove(0);
+        let target = store.rollback_target(&current).unwrap();
+        assert_eq!(("c", 4), (target.commit.as_str(), target.deployed_at));
+    }
+
+    #[test]
+    fn failed_write_keeps_state() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let data_dir = dir.path().join("data");
+        let store = StateStore::open(Some(&data_dir)).unwrap();
+        deploy(&store, "a", 1, "alice");
+
+        fs::remove_dir_all(&data_dir).unwrap();
+        let env = Environment {
+            commit: "b".into(),
+            ..environment()
+        };
+        assert!(store.record_deployment(env, "bob").is_err());
+        assert_eq!("a", store.environments()[0].commit);
+        assert_eq!(1, store.deployments(None, "preview").len());
+
+        fs::create_dir_all(&data_dir).unwrap();
+        deploy(&store, "c", 2, "bob");
+        let reopened = StateStore::open(Some(&data_dir)).unwrap();
+        assert_eq!(
+            vec!["c", "a"],
+            reopened
+                .deployments(None, "preview")
+                .iter()
+                .map(|d| d.commit.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+}